@@ -1,37 +1,102 @@
 use ring::aead::{CHACHA20_POLY1305, OpeningKey, SealingKey, UnboundKey, BoundKey, Nonce, NonceSequence, Aad};
 use ring::constant_time::verify_slices_are_equal;
+use ring::digest::{digest, SHA256};
+use ring::hkdf::{Salt, HKDF_SHA256};
+use ring::hmac;
 use ring::error::Unspecified;
 use ring::rand::{SecureRandom, SystemRandom};
 use std::time::SystemTime;
 
 
 const KEYSIZE: usize = 32;
+const KEY_ID_SIZE: usize = 4;
 const NONCE_SIZE: usize = 12;
+const HEADER_SIZE: usize = KEY_ID_SIZE + NONCE_SIZE;
 const DATE_SIZE: usize = 8;
 const TAG_SIZE: usize = 64;
 const SIG_SIZE: usize = 16;
-const TOKEN_SIZE: usize = NONCE_SIZE + TAG_SIZE + SIG_SIZE;
-const COOKIE_SIZE: usize = NONCE_SIZE + DATE_SIZE + TAG_SIZE + SIG_SIZE;
+const TOKEN_SIZE: usize = HEADER_SIZE + DATE_SIZE + TAG_SIZE + SIG_SIZE;
+const COOKIE_SIZE: usize = HEADER_SIZE + DATE_SIZE + TAG_SIZE + SIG_SIZE;
+pub const TOKEN_PAIR_SIZE: usize = TOKEN_SIZE + COOKIE_SIZE;
+const SIGNED_NONCE_SIZE: usize = 16;
+const SIGNED_HEADER_SIZE: usize = KEY_ID_SIZE + DATE_SIZE + SIGNED_NONCE_SIZE;
+const SIGNED_TOKEN_SIZE: usize = SIGNED_HEADER_SIZE + 32;
+const STORED_ID_SIZE: usize = 32;
+const MASKED_ID_SIZE: usize = 2 * STORED_ID_SIZE;
+const STORED_NONCE_SIZE: usize = 16;
+const STORED_HEADER_SIZE: usize = STORED_ID_SIZE + STORED_NONCE_SIZE + KEY_ID_SIZE;
+const STORED_TOKEN_SIZE: usize = STORED_HEADER_SIZE + 32;
+const MASK_SIZE: usize = 32;
+const MASKED_TOKEN_SIZE: usize = 2 * MASK_SIZE;
 
 
-pub struct CsrfProtection {
+/// Derive a key dedicated to csrf protection from some master key material, so that the key used
+/// for tokens is different from the one Rocket use for private cookies.
+pub fn derive_key(material: &[u8]) -> [u8; KEYSIZE] {
+    let mut key = [0; KEYSIZE];
+    Salt::new(HKDF_SHA256, b"rocket_csrf")
+        .extract(material)
+        .expand(&[b"csrf token encryption key"], HKDF_SHA256)
+        .and_then(|okm| okm.fill(&mut key))
+        .expect("HKDF-SHA256 output is exactly one key long");
+    key
+}
+
+struct Key {
+    id: [u8; KEY_ID_SIZE],
     aead_key: [u8; KEYSIZE],
+    mac_key: hmac::Key,
+}
+
+impl Key {
+    fn new(aead_key: [u8; KEYSIZE]) -> Self {
+        let mut id = [0; KEY_ID_SIZE];
+        id.copy_from_slice(&digest(&SHA256, &aead_key).as_ref()[..KEY_ID_SIZE]); //identify keys without revealing them
+        let mac_key = Salt::new(HKDF_SHA256, b"rocket_csrf")
+            .extract(&aead_key)
+            .expand(&[b"csrf token signing key"], hmac::HMAC_SHA256)
+            .map(hmac::Key::from)
+            .expect("HMAC-SHA256 keys are not too long for HKDF-SHA256"); //never sign with the encryption key
+        Key { id, aead_key, mac_key }
+    }
+}
+
+pub struct CsrfProtection {
+    active_key: Key,
+    previous_keys: Vec<Key>,
 }
 
 impl CsrfProtection {
-    pub fn from_key(aead_key: [u8; KEYSIZE]) -> Self {
-        CsrfProtection { aead_key }
+    pub fn from_keys(aead_key: [u8; KEYSIZE], previous_keys: Vec<[u8; KEYSIZE]>) -> Self {
+        CsrfProtection {
+            active_key: Key::new(aead_key),
+            previous_keys: previous_keys.into_iter().map(Key::new).collect(),
+        }
     }
 
-    pub fn parse_cookie<'a>(&self, cookie: &'a mut [u8]) -> Result<CsrfCookie<'a>, CsrfError> {
-        if cookie.len() < NONCE_SIZE {
-            return Err(CsrfError::ValidationError);// cookie is too short to be valid
+    fn find_key(&self, id: &[u8]) -> Result<&Key, CsrfError> {
+        Some(&self.active_key)
+            .into_iter()
+            .chain(self.previous_keys.iter())
+            .find(|key| key.id == id)
+            .ok_or(CsrfError::ValidationError)
+    }
+
+    fn open_in_place<'a>(&self, in_out: &'a mut [u8], session: &[u8], scope: &[u8]) -> Result<&'a mut [u8], CsrfError> {
+        if in_out.len() < HEADER_SIZE {
+            return Err(CsrfError::ValidationError);// too short to be valid
         }
-        let (nonce, token) = cookie.split_at_mut(NONCE_SIZE);
-        let unbound_key = UnboundKey::new(&CHACHA20_POLY1305, &self.aead_key).map_err(|_| CsrfError::UnknownError)?;
+        let (key_id, in_out) = in_out.split_at_mut(KEY_ID_SIZE);
+        let (nonce, in_out) = in_out.split_at_mut(NONCE_SIZE);
+        let key = self.find_key(key_id)?;
+        let unbound_key = UnboundKey::new(&CHACHA20_POLY1305, &key.aead_key).map_err(|_| CsrfError::UnknownError)?;
         let nonce = OneNonceSequence::new(Nonce::try_assume_unique_for_key(nonce).map_err(|_| CsrfError::ValidationError)?);
         let mut key = OpeningKey::new(unbound_key, nonce);
-        let token = key.open_in_place(Aad::from(&[]), token).map_err(|_| CsrfError::ValidationError)?;
+        key.open_in_place(Aad::from(associated_data(key_id, session, scope)), in_out).map_err(|_| CsrfError::ValidationError)
+    }
+
+    pub fn parse_cookie<'a>(&self, cookie: &'a mut [u8], session: &[u8]) -> Result<CsrfCookie<'a>, CsrfError> {
+        let token = self.open_in_place(cookie, session, &[])?;
         if token.len() < DATE_SIZE {// shorter than a timestamp, must be invalid
             return Err(CsrfError::ValidationError);
         }
@@ -45,68 +110,262 @@ impl CsrfProtection {
         })
     }
 
-    pub fn parse_token<'a>(&self, token: &'a mut [u8]) -> Result<CsrfToken<'a>, CsrfError> {
-        if token.len() < NONCE_SIZE {
-            return Err(CsrfError::ValidationError);// cookie is too short to be valid
+    pub fn parse_token(&self, token: &mut [u8], session: &[u8], scope: &[u8], masked: bool) -> Result<CsrfToken, CsrfError> {
+        if masked && token.len() == MASKED_TOKEN_SIZE && scope.is_empty() {
+            let (pad, masked) = token.split_at(MASK_SIZE);
+            return Ok(CsrfToken {
+                token: pad.iter().zip(masked).map(|(p, m)| p ^ m).collect(),
+                id: pad.to_vec(),
+                expires: None,
+            });
+        } //masked tokens are not encrypted, unmask their secret, their random pad identify them
+        let id = token.get(KEY_ID_SIZE..HEADER_SIZE).map(<[u8]>::to_vec).unwrap_or_default(); //nonces are random and authenticated, so they uniquely identify tokens
+        let token = self.open_in_place(token, session, scope)?;
+        if token.len() < DATE_SIZE {
+            return Err(CsrfError::ValidationError);
         }
-        let (nonce, token) = token.split_at_mut(NONCE_SIZE);
-        let unbound_key = UnboundKey::new(&CHACHA20_POLY1305, &self.aead_key).map_err(|_| CsrfError::ValidationError)?;
-        let nonce = OneNonceSequence::new(Nonce::try_assume_unique_for_key(nonce).map_err(|_| CsrfError::ValidationError)?);
-        let mut key = OpeningKey::new(unbound_key, nonce);
-        // let token = open_in_place(&key, nonce, &[], 0, token).map_err(|_| CsrfError::ValidationError)?;
-        let token = key.open_in_place(Aad::from(&[]), token).map_err(|_| CsrfError::ValidationError)?;
+        let (expires, token) = token.split_at(DATE_SIZE);
+        let mut date = [0; DATE_SIZE];
+        date.copy_from_slice(expires);
         Ok(CsrfToken{
-            token,
+            token: token.to_vec(),
+            id,
+            expires: Some(u64::from_be_bytes(date)),
         })
     }
 
-    pub fn verify_token_pair(&self, token: &CsrfToken, cookie: &CsrfCookie) -> bool {
-        let token_ok = verify_slices_are_equal(token.token,cookie.token).is_ok();
-        let not_expired = cookie.time_left() > 0; 
+    pub fn verify_token_pair(&self, token: &CsrfToken, cookie: &CsrfCookie) -> Result<(), CsrfError> {
+        //masked and scoped tokens may only hold the beginning of the secret, which is enough to prove it is known
+        let secret = cookie.token.get(..token.token.len()).filter(|_| token.token.len() >= MASK_SIZE);
+        let token_ok = secret.is_some_and(|secret| verify_slices_are_equal(&token.token, secret).is_ok());
+        let not_expired = cookie.time_left() > 0;
 
-        token_ok && not_expired
+        if !not_expired {
+            Err(CsrfError::Expired)
+        } else if !token_ok {
+            Err(CsrfError::Mismatch)
+        } else {
+            Ok(())
+        }
     }
 
-    pub fn generate_token_pair<'a>(&self, previous_token: Option<CsrfCookie>, ttl_seconds: u64, source_buffer: &'a mut[u8; TOKEN_SIZE + COOKIE_SIZE]) -> Result<(&'a[u8], &'a[u8]), CsrfError> {
+    pub fn generate_token_pair<'a>(&self, previous_token: Option<CsrfCookie>, ttl_seconds: u64, session: &[u8], source_buffer: &'a mut[u8; TOKEN_PAIR_SIZE]) -> Result<(&'a[u8], &'a[u8]), CsrfError> {
         let (token, cookie) = source_buffer.split_at_mut(TOKEN_SIZE);
+        let secret = new_secret(previous_token)?; //tokens made with previous keys are resealed with the active one
+        self.seal_secret(&secret, ttl_seconds, token, session, &[])?;
+        self.seal_cookie(&secret, ttl_seconds, session, cookie)?;
+        Ok((token, cookie))
+    }
+
+    /// Make a cookie, and a token holding the beginning of its secret masked by a random one-time
+    /// pad. Masked tokens are shorter, and change at each request like encrypted ones, so that
+    /// compression of pages doesn't leak them (BREACH).
+    pub fn generate_masked_token_pair<'a>(&self, previous_token: Option<CsrfCookie>, ttl_seconds: u64, session: &[u8], source_buffer: &'a mut[u8; TOKEN_PAIR_SIZE]) -> Result<(Vec<u8>, &'a[u8]), CsrfError> {
+        let cookie = &mut source_buffer[TOKEN_SIZE..];
+        let secret = new_secret(previous_token)?;
+        self.seal_cookie(&secret, ttl_seconds, session, cookie)?;
+
+        let mut token = vec![0; MASKED_TOKEN_SIZE];
+        let (pad, masked) = token.split_at_mut(MASK_SIZE);
+        SystemRandom::new().fill(pad).map_err(|_| CsrfError::UnknownError)?;
+        for ((m, p), s) in masked.iter_mut().zip(pad.iter()).zip(&secret[..MASK_SIZE]) {
+            *m = p ^ s;
+        }
+        Ok((token, cookie))
+    }
+
+    /// Make a token which is only valid for a given scope, and for the next `ttl_seconds`, from an
+    /// unscoped one.
+    pub fn scope_token(&self, token: &[u8], ttl_seconds: u64, session: &[u8], scope: &[u8], masked: bool) -> Result<Vec<u8>, CsrfError> {
+        let mut unscoped = token.to_vec();
+        let secret = self.parse_token(&mut unscoped, session, &[], masked)?;
+        let mut scoped = vec![0; HEADER_SIZE + DATE_SIZE + secret.token.len() + SIG_SIZE]; //secrets of masked tokens are shorter
+        self.seal_secret(&secret.token, ttl_seconds, &mut scoped, session, scope)?;
+        Ok(scoped)
+    }
+
+    /// Make a token which is valid without any cookie, signing the session it is issued for, its
+    /// expiration date and a random nonce.
+    pub fn generate_signed_token(&self, ttl_seconds: u64, session: &[u8], scope: &[u8]) -> Result<Vec<u8>, CsrfError> {
         let expire = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs() + ttl_seconds).map_err(|_| CsrfError::UnknownError)?;
-        cookie[NONCE_SIZE..DATE_SIZE+NONCE_SIZE].copy_from_slice(&expire.to_be_bytes());
-        
-        let rand = SystemRandom::new();
-        if let Some(previous_token) = previous_token {
-            cookie[NONCE_SIZE+DATE_SIZE..TAG_SIZE+NONCE_SIZE+DATE_SIZE].copy_from_slice(previous_token.token);
-            token[NONCE_SIZE..TAG_SIZE+NONCE_SIZE].copy_from_slice(previous_token.token);
+        let mut token = Vec::with_capacity(SIGNED_TOKEN_SIZE);
+        token.extend_from_slice(&self.active_key.id);
+        token.extend_from_slice(&expire.to_be_bytes());
+        let mut nonce = [0; SIGNED_NONCE_SIZE];
+        SystemRandom::new().fill(&mut nonce).map_err(|_| CsrfError::UnknownError)?;
+        token.extend_from_slice(&nonce);
+        let tag = hmac::sign(&self.active_key.mac_key, &associated_data(&token, session, scope));
+        token.extend_from_slice(tag.as_ref());
+        Ok(token)
+    }
+
+    pub fn verify_signed_token(&self, token: &[u8], session: &[u8], scope: &[u8]) -> Result<SignedToken, CsrfError> {
+        if token.len() != SIGNED_TOKEN_SIZE {
+            return Err(CsrfError::ValidationError);
+        }
+        let (header, tag) = token.split_at(SIGNED_HEADER_SIZE);
+        let key = self.find_key(&header[..KEY_ID_SIZE])?;
+        hmac::verify(&key.mac_key, &associated_data(header, session, scope), tag).map_err(|_| CsrfError::ValidationError)?;
+
+        let mut expires = [0; DATE_SIZE];
+        expires.copy_from_slice(&header[KEY_ID_SIZE..KEY_ID_SIZE+DATE_SIZE]);
+        let mut id = [0; SIGNED_NONCE_SIZE];
+        id.copy_from_slice(&header[KEY_ID_SIZE+DATE_SIZE..]);
+        let token = SignedToken {
+            id,
+            expires: u64::from_be_bytes(expires),
+        };
+        if token.time_left() == 0 {
+            Err(CsrfError::Expired)
         } else {
-            rand.fill(&mut token[NONCE_SIZE..TAG_SIZE+NONCE_SIZE]).map_err(|_| CsrfError::UnknownError)?;
-            cookie[NONCE_SIZE+DATE_SIZE..TAG_SIZE+NONCE_SIZE+DATE_SIZE].copy_from_slice(&token[NONCE_SIZE..TAG_SIZE+NONCE_SIZE]);
+            Ok(token)
+        } //only tell a token expired once it is known to be authentic
+    }
+
+    /// Generate the random id of a token kept in a server-side store.
+    pub fn generate_stored_id(&self) -> Result<Vec<u8>, CsrfError> {
+        let mut id = vec![0; STORED_ID_SIZE];
+        SystemRandom::new().fill(&mut id).map_err(|_| CsrfError::UnknownError)?;
+        Ok(id)
+    }
+
+    /// Mask the id of a stored token with a random one-time pad, so that a token reused for
+    /// several pages still differs in each of them, and compression of pages doesn't leak it
+    /// (BREACH).
+    pub fn mask_stored_id(&self, id: &[u8]) -> Result<Vec<u8>, CsrfError> {
+        let mut token = vec![0; MASKED_ID_SIZE];
+        let (pad, masked) = token.split_at_mut(STORED_ID_SIZE);
+        SystemRandom::new().fill(pad).map_err(|_| CsrfError::UnknownError)?;
+        for ((m, p), i) in masked.iter_mut().zip(pad.iter()).zip(id) {
+            *m = p ^ i;
         }
-        
-        let mut nonce = [0;NONCE_SIZE];
-        
-        rand.fill(&mut nonce).map_err(|_| CsrfError::UnknownError)?;
-        self.seal_in_place(nonce, token, TOKEN_SIZE)?;
+        Ok(token)
+    }
 
-        rand.fill(&mut nonce).map_err(|_| CsrfError::UnknownError)?;
-        self.seal_in_place(nonce, cookie, COOKIE_SIZE)?;
+    /// Make a token which is only valid for a given scope from a masked stored token, by signing
+    /// its id along with a random nonce, the session and scope, so that scoped tokens need not be
+    /// stored, but can still be told apart.
+    pub fn scope_stored_id(&self, masked: &[u8], session: &[u8], scope: &[u8]) -> Result<Vec<u8>, CsrfError> {
+        let mut token = Vec::with_capacity(STORED_TOKEN_SIZE);
+        token.extend_from_slice(&unmask_stored_id(masked)?);
+        let mut nonce = [0; STORED_NONCE_SIZE];
+        SystemRandom::new().fill(&mut nonce).map_err(|_| CsrfError::UnknownError)?;
+        token.extend_from_slice(&nonce);
+        token.extend_from_slice(&self.active_key.id);
+        let tag = hmac::sign(&self.active_key.mac_key, &associated_data(&token, session, scope));
+        token.extend_from_slice(tag.as_ref());
+        Ok(token)
+    }
 
-        Ok((token, cookie))
+    /// Get the id of a stored token, and the unique id of the token itself, verifying its
+    /// signature if it was scoped. Unscoped tokens are their masked id, which is only verified by
+    /// looking it up in the store, while scoped tokens are identified by their id and nonce.
+    pub fn parse_stored_token(&self, token: &[u8], session: &[u8], scope: &[u8]) -> Result<(Vec<u8>, Vec<u8>), CsrfError> {
+        if token.len() == MASKED_ID_SIZE && scope.is_empty() {
+            let id = unmask_stored_id(token)?;
+            return Ok((id.clone(), id));
+        }
+        if token.len() != STORED_TOKEN_SIZE {
+            return Err(CsrfError::ValidationError);
+        }
+        let (header, tag) = token.split_at(STORED_HEADER_SIZE);
+        let key = self.find_key(&header[STORED_ID_SIZE+STORED_NONCE_SIZE..])?;
+        hmac::verify(&key.mac_key, &associated_data(header, session, scope), tag).map_err(|_| CsrfError::ValidationError)?;
+        Ok((header[..STORED_ID_SIZE].to_vec(), header[..STORED_ID_SIZE+STORED_NONCE_SIZE].to_vec()))
     }
 
-    fn seal_in_place(&self, nonce: [u8; NONCE_SIZE], in_out: &mut [u8], in_out_size: usize) -> Result<(), CsrfError> {
-        in_out[..NONCE_SIZE].copy_from_slice(&nonce);
-        let unbound_key = UnboundKey::new(&CHACHA20_POLY1305, &self.aead_key).map_err(|_| CsrfError::ValidationError)?;
+    fn seal_secret(&self, secret: &[u8], ttl_seconds: u64, token: &mut [u8], session: &[u8], scope: &[u8]) -> Result<(), CsrfError> {
+        //tokens carry their expiration date, so that they can't outlive one-time use records
+        let expire = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs() + ttl_seconds).map_err(|_| CsrfError::UnknownError)?;
+        token[HEADER_SIZE..HEADER_SIZE+DATE_SIZE].copy_from_slice(&expire.to_be_bytes());
+        token[HEADER_SIZE+DATE_SIZE..HEADER_SIZE+DATE_SIZE+secret.len()].copy_from_slice(secret);
+        let mut nonce = [0;NONCE_SIZE];
+        SystemRandom::new().fill(&mut nonce).map_err(|_| CsrfError::UnknownError)?;
+        self.seal_in_place(nonce, token, token.len(), session, scope)
+    }
+
+    fn seal_cookie(&self, secret: &[u8], ttl_seconds: u64, session: &[u8], cookie: &mut [u8]) -> Result<(), CsrfError> {
+        let expire = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs() + ttl_seconds).map_err(|_| CsrfError::UnknownError)?;
+        cookie[HEADER_SIZE..DATE_SIZE+HEADER_SIZE].copy_from_slice(&expire.to_be_bytes());
+        cookie[HEADER_SIZE+DATE_SIZE..TAG_SIZE+HEADER_SIZE+DATE_SIZE].copy_from_slice(secret);
+        let mut nonce = [0;NONCE_SIZE];
+        SystemRandom::new().fill(&mut nonce).map_err(|_| CsrfError::UnknownError)?;
+        self.seal_in_place(nonce, cookie, COOKIE_SIZE, session, &[])
+    }
+
+    fn seal_in_place(&self, nonce: [u8; NONCE_SIZE], in_out: &mut [u8], in_out_size: usize, session: &[u8], scope: &[u8]) -> Result<(), CsrfError> {
+        in_out[..KEY_ID_SIZE].copy_from_slice(&self.active_key.id);
+        in_out[KEY_ID_SIZE..HEADER_SIZE].copy_from_slice(&nonce);
+        let unbound_key = UnboundKey::new(&CHACHA20_POLY1305, &self.active_key.aead_key).map_err(|_| CsrfError::ValidationError)?;
         let nonce_sequence = OneNonceSequence::new(Nonce::assume_unique_for_key(nonce));
         let mut key = SealingKey::new(unbound_key, nonce_sequence);
-        let mut io = Vec::from(&in_out[NONCE_SIZE..(in_out_size - SIG_SIZE)]);
-        key.seal_in_place_append_tag(Aad::from(&[]), &mut io).map_err(|_| CsrfError::UnknownError)?;
-        in_out[NONCE_SIZE..].copy_from_slice(&io);
+        let mut io = Vec::from(&in_out[HEADER_SIZE..(in_out_size - SIG_SIZE)]);
+        key.seal_in_place_append_tag(Aad::from(associated_data(&self.active_key.id, session, scope)), &mut io).map_err(|_| CsrfError::UnknownError)?;
+        in_out[HEADER_SIZE..].copy_from_slice(&io);
 
         Ok(())
     }
 }
 
-pub struct CsrfToken<'a> {
-    token: &'a[u8],
+fn associated_data(header: &[u8], session: &[u8], scope: &[u8]) -> Vec<u8> {
+    //authenticate the fixed size header (key id, date and nonce of signed tokens, or id of stored
+    //ones), and bind to the session and scope, the session is prefixed by its length so it can't
+    //be confused with the scope
+    let mut aad = Vec::with_capacity(header.len() + 8 + session.len() + scope.len());
+    aad.extend_from_slice(header);
+    aad.extend_from_slice(&(session.len() as u64).to_be_bytes());
+    aad.extend_from_slice(session);
+    aad.extend_from_slice(scope);
+    aad
+}
+
+fn unmask_stored_id(token: &[u8]) -> Result<Vec<u8>, CsrfError> {
+    if token.len() != MASKED_ID_SIZE {
+        return Err(CsrfError::ValidationError);
+    }
+    let (pad, masked) = token.split_at(STORED_ID_SIZE);
+    Ok(pad.iter().zip(masked).map(|(p, m)| p ^ m).collect())
+}
+
+fn new_secret(previous_token: Option<CsrfCookie>) -> Result<[u8; TAG_SIZE], CsrfError> {
+    let mut secret = [0; TAG_SIZE];
+    match previous_token {
+        Some(previous_token) => secret.copy_from_slice(previous_token.token),
+        None => SystemRandom::new().fill(&mut secret).map_err(|_| CsrfError::UnknownError)?,
+    }
+    Ok(secret)
+}
+
+pub struct CsrfToken {
+    token: Vec<u8>,
+    id: Vec<u8>,
+    expires: Option<u64>, //masked tokens are only bound to the cookie expiration
+}
+
+impl CsrfToken {
+    pub fn id(&self) -> &[u8] {
+        &self.id
+    }
+
+    pub fn time_left(&self) -> Option<u64> {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).ok()?;
+        Some(self.expires?.saturating_sub(now.as_secs()))
+    }
+}
+
+pub struct SignedToken {
+    id: [u8; SIGNED_NONCE_SIZE],
+    expires: u64,
+}
+
+impl SignedToken {
+    pub fn id(&self) -> &[u8] {
+        &self.id
+    }
+
+    pub fn time_left(&self) -> u64 {
+        SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).ok().and_then(|now| self.expires.checked_sub(now.as_secs())).unwrap_or(0)
+    }
 }
 
 pub struct CsrfCookie<'a> {
@@ -120,8 +379,11 @@ impl<'a> CsrfCookie<'a> {
     }
 }
 
+#[derive(Debug)]
 pub enum CsrfError {
     ValidationError,
+    Expired,
+    Mismatch,
     UnknownError,
 }
 
@@ -0,0 +1,224 @@
+use rocket::figment::value::Value;
+use rocket::http::Method;
+use rocket::serde::Deserialize;
+use std::error::Error;
+use std::fmt;
+
+use crate::path::{Path, PathError};
+
+/// Configuration read from the `csrf` table of Rocket's configuration.
+///
+/// The `CsrfConfig` type is extracted from Rocket's figment when the application ignite, so its
+/// values may be set per profile in `Rocket.toml`, or via `ROCKET_CSRF` environment variable.
+/// Values present in the configuration replace those set on the
+/// [CsrfFairingBuilder](struct.CsrfFairingBuilder.html), except for lists, which are appended to
+/// the builder's ones.
+///
+/// # Example
+///
+/// ```toml
+/// [default.csrf]
+/// duration = 3600
+/// default_target = { path = "/csrf-violation?from=<uri>", method = "GET" }
+/// protected_methods = ["POST", "PUT", "DELETE", "PATCH"]
+/// exceptions = [
+///     { source = "/some/<other>/path", destination = "/csrf-error?where=<other>", method = "GET" },
+///     { source = "/api/session", source_method = "DELETE", destination = "/api/session" },
+/// ]
+/// exempt_routes = ["payment_webhook"]
+/// auto_insert = true
+/// auto_insert_disable_prefix = ["/api"]
+/// auto_insert_max_size = 32768
+/// secure_cookies = true
+/// previous_secret_keys = ["hPRYyVRiMyxpw5sBB1XeCMN1kFsDCqKvBi2QJxBVHQk="]
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(crate = "rocket::serde", default, deny_unknown_fields)]
+pub struct CsrfConfig {
+    /// Timeout of tokens, in seconds, see `set_timeout`.
+    pub duration: Option<u64>,
+    /// Route to which violations are redirected, see `set_default_target`.
+    pub default_target: Option<TargetConfig>,
+    /// Methods of requests which are verified, see `set_protected_methods`.
+    pub protected_methods: Option<Vec<Method>>,
+    /// Exceptions added to the builder's ones, see `add_exceptions`.
+    pub exceptions: Vec<ExceptionConfig>,
+    /// Names of routes added to the builder's exempt ones, see `add_exempt_route_names`.
+    pub exempt_routes: Vec<String>,
+    /// Whether tokens are inserted in forms, see `set_auto_insert`.
+    pub auto_insert: Option<bool>,
+    /// Prefixes added to the builder's ones, see `set_auto_insert_disable_prefix`.
+    pub auto_insert_disable_prefix: Vec<String>,
+    /// Size under which bodies are processed at once, see `set_auto_insert_max_chunk_size`.
+    pub auto_insert_max_size: Option<u64>,
+    /// Whether cookies are only sent over https, see `set_secure_cookies`.
+    pub secure_cookies: Option<bool>,
+    /// Previous values of Rocket's `secret_key`, added to the builder's ones, see
+    /// `set_previous_secret_keys`.
+    pub previous_secret_keys: Vec<Value>,
+}
+
+/// Route to which violations are redirected.
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde", deny_unknown_fields)]
+pub struct TargetConfig {
+    /// Path of the route, which may contain `<uri>` and `<method>` parts.
+    pub path: String,
+    /// Method of the route, `GET` if absent.
+    #[serde(default = "default_method")]
+    pub method: Method,
+}
+
+/// Exception to the default redirection of violations.
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde", deny_unknown_fields)]
+pub struct ExceptionConfig {
+    /// Path matched against the request.
+    pub source: String,
+    /// Method matched against the request, any if absent.
+    pub source_method: Option<Method>,
+    /// Path to which matching requests are rerouted.
+    pub destination: String,
+    /// Method to which matching requests are rerouted, unchanged if absent.
+    pub method: Option<Method>,
+}
+
+fn default_method() -> Method {
+    Method::Get
+}
+
+/// Error returned when a fairing can't be built from its configuration.
+///
+/// The `ConfigError` type is returned by
+/// [CsrfFairingBuilder::finalize](struct.CsrfFairingBuilder.html#method.finalize), and reported
+/// when Rocket ignite if the [CsrfConfig](struct.CsrfConfig.html) read from Rocket's
+/// configuration is invalid.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// A path pattern can't be parsed.
+    InvalidPath {
+        /// The invalid pattern.
+        path: String,
+        /// Why it is invalid.
+        error: PathError,
+    },
+    /// The default target use a dynamic part other than `<uri>` and `<method>`.
+    InvalidDefaultTarget {
+        /// The default target.
+        path: String,
+        /// The unknown dynamic part.
+        param: String,
+    },
+    /// An exception's destination use a dynamic part which its source doesn't capture.
+    UncapturedParam {
+        /// The source of the exception.
+        source: String,
+        /// The destination of the exception.
+        destination: String,
+        /// The uncaptured dynamic part.
+        param: String,
+    },
+    /// An exception's destination contains globs, regexes or optional segments.
+    PatternInDestination {
+        /// The destination of the exception.
+        destination: String,
+    },
+    /// Patterns are too many or too complex to be compiled together.
+    TooManyPatterns,
+    /// Stateless or stored tokens are enabled, but no session binder was set.
+    MissingSessionBinder,
+    /// Masked tokens and one-time tokens are both enabled.
+    MaskedOneTimeTokens,
+    /// A route exception reference a route which isn't mounted.
+    UnknownRoute {
+        /// The name, or method and uri, of the route.
+        route: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::InvalidPath { path, error } => write!(f, "invalid path `{}`: {}", path, error),
+            ConfigError::InvalidDefaultTarget { path, param } => write!(
+                f,
+                "default target `{}` use `<{}>`, only `<uri>` and `<method>` are available",
+                path, param
+            ),
+            ConfigError::UncapturedParam { source, destination, param } => write!(
+                f,
+                "exception destination `{}` use `<{}>`, which is not captured by `{}`",
+                destination, param, source
+            ),
+            ConfigError::PatternInDestination { destination } => write!(
+                f,
+                "exception destination `{}` can't contain globs, regexes or optional segments",
+                destination
+            ),
+            ConfigError::TooManyPatterns => f.write_str("patterns are too complex to be compiled together"),
+            ConfigError::MissingSessionBinder => f.write_str("stateless and stored tokens require a session binder"),
+            ConfigError::MaskedOneTimeTokens => f.write_str("masked tokens can't be one-time tokens"),
+            ConfigError::UnknownRoute { route } => write!(f, "no mounted route match `{}`", route),
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConfigError::InvalidPath { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+pub fn parse_path(path: &str) -> Result<Path, ConfigError> {
+    Path::parse(path).map_err(|error| ConfigError::InvalidPath {
+        path: path.to_owned(),
+        error,
+    })
+}
+
+/// Parse a prefix for which tokens are not inserted.
+pub fn parse_prefix(prefix: &str) -> Result<Path, ConfigError> {
+    Path::parse_prefix(prefix).map_err(|error| ConfigError::InvalidPath {
+        path: prefix.to_owned(),
+        error,
+    })
+}
+
+/// Parse the default target, verifying it is valid as default path, i.e. its only dynamic parts
+/// are <uri> and <method>.
+pub fn parse_default_target(path: &str) -> Result<Path, ConfigError> {
+    let default_target = parse_path(path)?;
+    if let Some(param) = default_target.params().find(|&param| param != "uri" && param != "method") {
+        return Err(ConfigError::InvalidDefaultTarget {
+            path: path.to_owned(),
+            param: param.to_owned(),
+        });
+    }
+    Ok(default_target)
+}
+
+/// Parse an exception, verifying its destination only use dynamic parts captured by its source.
+pub fn parse_exception(
+    source: &str,
+    destination: &str,
+    method: Option<Method>,
+) -> Result<(Path, Path, Option<Method>), ConfigError> {
+    let src = parse_path(source)?;
+    let dst = parse_path(destination)?;
+    if dst.has_patterns() {
+        return Err(ConfigError::PatternInDestination {
+            destination: destination.to_owned(),
+        });
+    }
+    if let Some(param) = dst.params().find(|&param| !src.params().any(|p| p == param)) {
+        return Err(ConfigError::UncapturedParam {
+            source: source.to_owned(),
+            destination: destination.to_owned(),
+            param: param.to_owned(),
+        });
+    }
+    Ok((src, dst, method))
+}
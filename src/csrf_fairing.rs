@@ -1,24 +1,37 @@
-use data_encoding::{BASE64, BASE64URL_NOPAD};
+use data_encoding::{BASE64, BASE64URL_NOPAD, HEXLOWER_PERMISSIVE};
 use ring::rand::{SecureRandom, SystemRandom};
-use rocket::fairing::{Fairing, Info, Kind};
-use rocket::http::uri::{Origin, Uri};
-use rocket::http::Cookie;
+use rocket::fairing::{self, Fairing, Info, Kind};
+use rocket::http::uri::Origin;
 use rocket::http::Method::{self, *};
+use rocket::http::{Cookie, RawStr, SameSite, Status};
 use rocket::outcome::Outcome;
-use rocket::response::Body::Sized;
-use rocket::{Data, Request, Response, Rocket, State};
+use rocket::time::Duration;
+use rocket::route::{self, Handler, Route};
+use rocket::figment::value::Value;
+use rocket::figment::Figment;
+use rocket::{Build, Config, Data, Request, Response, Rocket};
 use std::collections::HashMap;
-use std::env;
-use std::io::{Cursor, Read};
-use std::str::from_utf8;
-use time::Duration;
-
-use crypto::CsrfProtection;
-use csrf_proxy::CsrfProxy;
-use csrf_token::CsrfToken;
-use path::Path;
-use utils::parse_args;
-use {CSRF_COOKIE_NAME, CSRF_FORM_FIELD, CSRF_FORM_FIELD_MULTIPART};
+use std::convert::TryFrom;
+use std::io::Cursor;
+use std::str::{from_utf8, FromStr};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::io::AsyncReadExt;
+
+use crate::crypto::{derive_key, CsrfError, CsrfProtection};
+use crate::csrf_config::{parse_default_target, parse_exception, parse_path, parse_prefix, ConfigError, CsrfConfig};
+use crate::csrf_guard::CsrfVerdict;
+use crate::csrf_proxy::CsrfProxy;
+use crate::csrf_token::CsrfToken;
+use crate::csrf_violation::{CsrfViolation, ViolationUri};
+use crate::path::{normalize, Path};
+use crate::path_set::PathSet;
+use crate::replay_store::{MemoryReplayStore, ReplayStore};
+use crate::route_table::{RouteRef, RouteTable};
+use crate::session_binder::SessionBinder;
+use crate::token_store::{MemoryTokenStore, TokenStore};
+use crate::utils::{parse_args, parse_origin, resolve_action};
+use crate::{CSRF_COOKIE_NAME, CSRF_FORM_FIELD, CSRF_VIOLATION_PATH};
 
 /// Builder for [CsrfFairing](struct.CsrfFairing.html)
 ///
@@ -42,26 +55,52 @@ use {CSRF_COOKIE_NAME, CSRF_FORM_FIELD, CSRF_FORM_FIELD_MULTIPART};
 /// # extern crate rocket;
 /// # extern crate rocket_csrf;
 /// use rocket_csrf::CsrfFairingBuilder;
-/// # use rocket::Rocket;
 ///
-/// # fn main() {
-///     Rocket::ignite()
+/// # #[rocket::main]
+/// # async fn main() {
+///     let _ = rocket::build()
 ///         .attach(CsrfFairingBuilder::new()
 ///                 .set_default_target("/csrf-violation".to_owned(), rocket::http::Method::Get)
 ///                 .finalize().unwrap())
 ///         //add your routes, other fairings...
-///         .launch();
+///         .launch()
+///         .await;
 /// # }
 /// ```
-
 pub struct CsrfFairingBuilder {
     duration: u64,
     default_target: (String, Method),
-    exceptions: Vec<(String, String, Option<Method>)>,
+    protected_methods: Vec<Method>,
+    exceptions: Vec<(Option<Method>, String, String, Option<Method>)>,
+    route_exceptions: Vec<(RouteRef, String, Option<Method>)>,
+    exempt_routes: Vec<RouteRef>,
     secret: Option<[u8; 32]>,
+    previous_secrets: Vec<[u8; 32]>,
+    previous_secret_keys: Vec<Vec<u8>>,
     auto_insert: bool,
     auto_insert_disable_prefix: Vec<String>,
     auto_insert_max_size: u64,
+    cookie_name: String,
+    form_field: String,
+    token_headers: Vec<String>,
+    double_submit: Option<(String, String)>,
+    secure_cookies: bool,
+    check_mode: CheckMode,
+    allowed_origins: Vec<String>,
+    fetch_metadata: bool,
+    fetch_metadata_allow_navigation: bool,
+    fetch_metadata_reject_same_site: bool,
+    fetch_metadata_allowed_paths: Vec<String>,
+    enforce_globally: bool,
+    reject_status: Option<Status>,
+    session_binder: Option<Arc<dyn SessionBinder>>,
+    scoped_tokens: bool,
+    require_scoped_form_tokens: bool,
+    one_time_tokens: bool,
+    replay_store: Option<Arc<dyn ReplayStore>>,
+    token_mode: TokenMode,
+    token_store: Option<Arc<dyn TokenStore>>,
+    masked_tokens: bool,
 }
 
 impl CsrfFairingBuilder {
@@ -70,11 +109,37 @@ impl CsrfFairingBuilder {
         CsrfFairingBuilder {
             duration: 60 * 60 * 12,
             default_target: (String::from("/"), Get),
+            protected_methods: vec![Post, Put, Delete, Patch, Trace],
             exceptions: Vec::new(),
+            route_exceptions: Vec::new(),
+            exempt_routes: Vec::new(),
             secret: None,
+            previous_secrets: Vec::new(),
+            previous_secret_keys: Vec::new(),
             auto_insert: true,
             auto_insert_disable_prefix: Vec::new(),
             auto_insert_max_size: 16 * 1024,
+            cookie_name: CSRF_COOKIE_NAME.to_owned(),
+            form_field: CSRF_FORM_FIELD.to_owned(),
+            token_headers: vec!["X-CSRF-Token".to_owned(), "X-XSRF-Token".to_owned()],
+            double_submit: None,
+            secure_cookies: true,
+            check_mode: CheckMode::TokenOnly,
+            allowed_origins: Vec::new(),
+            fetch_metadata: false,
+            fetch_metadata_allow_navigation: false,
+            fetch_metadata_reject_same_site: false,
+            fetch_metadata_allowed_paths: Vec::new(),
+            enforce_globally: true,
+            reject_status: None,
+            session_binder: None,
+            scoped_tokens: false,
+            require_scoped_form_tokens: false,
+            one_time_tokens: false,
+            replay_store: None,
+            token_mode: TokenMode::Cookie,
+            token_store: None,
+            masked_tokens: false,
         }
     }
 
@@ -86,8 +151,9 @@ impl CsrfFairingBuilder {
     }
 
     /// Set the default route when an invalide request is catched, you may add a <uri> as a segment
-    /// or a param to get the percent-encoded original target. You can also set the method of the
-    /// route to which you choosed to redirect.
+    /// or a param to get the percent-encoded original target, and a <method> to get the method of
+    /// the request, after any `_method` override. You can also set the method of the route to
+    /// which you choosed to redirect.
     ///
     /// # Example
     ///
@@ -95,30 +161,48 @@ impl CsrfFairingBuilder {
     /// # extern crate rocket;
     /// # extern crate rocket_csrf;
     /// use rocket_csrf::CsrfFairingBuilder;
-    /// # use rocket::Rocket;
     ///
-    /// fn main() {
-    ///     rocket::ignite()
+    /// #[rocket::main]
+    /// async fn main() {
+    ///     let _ = rocket::build()
     ///         .attach(rocket_csrf::CsrfFairingBuilder::new()
     ///                 .set_default_target("/csrf-violation".to_owned(), rocket::http::Method::Get)
     ///                 .finalize().unwrap())
     ///         //add your routes, other fairings...
-    ///         .launch();
+    ///         .launch()
+    ///         .await;
     /// }
     pub fn set_default_target(mut self, default_target: String, method: Method) -> Self {
         self.default_target = (default_target, method);
         self
     }
 
+    /// Set the methods of requests which are verified. Requests using other methods are always
+    /// accepted, so those should only be used by routes without side effects. Default is POST,
+    /// PUT, DELETE, PATCH and TRACE.
+    ///
+    /// Like Rocket's routing, this use the method set by the `_method` field of urlencoded forms,
+    /// so a form sent with POST and `_method=delete` is verified as a DELETE request. Method based
+    /// exceptions also match against this method.
+    pub fn set_protected_methods(mut self, methods: Vec<Method>) -> Self {
+        self.protected_methods = methods;
+        self
+    }
+
     /// Set the list of exceptions which will not be redirected to the default route, removing any
     /// previously added exceptions, to juste add exceptions use [`add_exceptions`] instead. A route may
     /// contain dynamic parts noted as <name>, which will be replaced in the target route.
     /// Note that this is not aware of Rocket's routes, so matching `/something/<dynamic>` while
     /// match against `/something/static`, even if those are different routes for Rocket. To
-    /// circunvence this issue, you can add a (not so) exception matching the static route before
-    /// the dynamic one, and redirect it to the default target manually.
+    /// circunvence this issue, use [`add_route_exceptions`] instead.
+    ///
+    /// Sources may also contain patterns which match without capturing: `*` inside a segment
+    /// match any characters but `/`, a `**` segment match any number of segments, and a segment
+    /// in brackets, like `[v1]`, is optional. A dynamic part may be restricted by a regex, as in
+    /// `<id:[0-9]+>`. Destinations can only contain static and dynamic parts.
     ///
     /// [`add_exceptions`]: #method.add_exceptions
+    /// [`add_route_exceptions`]: #method.add_route_exceptions
     ///
     /// # Example
     ///
@@ -126,10 +210,10 @@ impl CsrfFairingBuilder {
     /// # extern crate rocket;
     /// # extern crate rocket_csrf;
     /// use rocket_csrf::CsrfFairingBuilder;
-    /// # use rocket::Rocket;
     ///
-    /// fn main() {
-    ///     rocket::ignite()
+    /// #[rocket::main]
+    /// async fn main() {
+    ///     let _ = rocket::build()
     ///         .attach(rocket_csrf::CsrfFairingBuilder::new()
     ///                 .set_exceptions(vec![
     ///                     ("/some/path".to_owned(), "/some/path".to_owned(), Some(rocket::http::Method::Post)),//don't verify csrf token
@@ -137,26 +221,180 @@ impl CsrfFairingBuilder {
     ///                 ])
     ///                 .finalize().unwrap())
     ///         //add your routes, other fairings...
-    ///         .launch();
+    ///         .launch()
+    ///         .await;
     /// }
     /// ```
     pub fn set_exceptions(mut self, exceptions: Vec<(String, String, Option<Method>)>) -> Self {
-        self.exceptions = exceptions;
-        self
+        self.exceptions = Vec::new();
+        self.add_exceptions(exceptions)
     }
     /// Add the to list of exceptions which will not be redirected to the default route. See
     /// [`set_exceptions`] for more informations on how exceptions work.
     ///
     /// [`set_exceptions`]: #method.set_exceptions
     pub fn add_exceptions(mut self, exceptions: Vec<(String, String, Option<Method>)>) -> Self {
-        self.exceptions.extend(exceptions);
+        self.exceptions.extend(
+            exceptions
+                .into_iter()
+                .map(|(source, destination, method)| (None, source, destination, method)),
+        );
+        self
+    }
+
+    /// Add exceptions which only apply to requests using a given method, the first element of
+    /// each tuple, so that a path can be handled differently depending on the method used to
+    /// request it. See [`set_exceptions`] for more informations on how exceptions work.
+    ///
+    /// [`set_exceptions`]: #method.set_exceptions
+    ///
+    /// # Example
+    ///
+    ///  ```rust,no_run
+    /// # extern crate rocket;
+    /// # extern crate rocket_csrf;
+    /// use rocket::http::Method;
+    /// use rocket_csrf::CsrfFairingBuilder;
+    ///
+    /// #[rocket::main]
+    /// async fn main() {
+    ///     let _ = rocket::build()
+    ///         .attach(rocket_csrf::CsrfFairingBuilder::new()
+    ///                 .add_method_exceptions(vec![
+    ///                     (Method::Delete, "/api/session".to_owned(), "/api/session".to_owned(), None),//don't verify logout, POST is still verified
+    ///                 ])
+    ///                 .finalize().unwrap())
+    ///         //add your routes, other fairings...
+    ///         .launch()
+    ///         .await;
+    /// }
+    /// ```
+    pub fn add_method_exceptions(mut self, exceptions: Vec<(Method, String, String, Option<Method>)>) -> Self {
+        self.exceptions.extend(
+            exceptions
+                .into_iter()
+                .map(|(source_method, source, destination, method)| (Some(source_method), source, destination, method)),
+        );
+        self
+    }
+
+    /// Add an exception for requests routed to one of the given routes, wherever they are
+    /// mounted. Unlike [`set_exceptions`], this takes the ranks of mounted routes into account, so
+    /// an exception for `/something/<dynamic>` doesn't apply to requests for `/something/static`
+    /// if such a route is mounted. The destination may contain dynamic parts of the route's path.
+    /// Routes are resolved when Rocket ignite, which fails if a route isn't mounted by then.
+    /// Exceptions of the route a request is routed to are tried before path based ones.
+    ///
+    /// Requests are verified before Rocket routes them, so the route of a request is guessed as
+    /// the first mounted route, by rank, whose method, path, static query parameters and format
+    /// match it. This approximates Rocket's router, but can't know whether a route would forward,
+    /// for instance because a guard or a parameter fails, so when routes share a path, the
+    /// exception of the first one applies even to requests Rocket eventually routes to another.
+    ///
+    /// [`set_exceptions`]: #method.set_exceptions
+    ///
+    /// # Example
+    ///
+    ///  ```rust,no_run
+    /// # #[macro_use] extern crate rocket;
+    /// # extern crate rocket_csrf;
+    /// use rocket_csrf::CsrfFairingBuilder;
+    ///
+    /// #[post("/some/<other>/path")]
+    /// fn some_route(other: String) -> String {
+    ///     other
+    /// }
+    ///
+    /// #[rocket::main]
+    /// async fn main() {
+    ///     let _ = rocket::build()
+    ///         .attach(rocket_csrf::CsrfFairingBuilder::new()
+    ///                 .add_route_exceptions(routes![some_route], "/csrf-error?where=<other>".to_owned(), Some(rocket::http::Method::Get))
+    ///                 .finalize().unwrap())
+    ///         .mount("/", routes![some_route])
+    ///         //add your routes, other fairings...
+    ///         .launch()
+    ///         .await;
+    /// }
+    /// ```
+    pub fn add_route_exceptions(mut self, routes: Vec<Route>, destination: String, method: Option<Method>) -> Self {
+        self.route_exceptions.extend(
+            routes
+                .iter()
+                .map(|route| (RouteRef::from(route), destination.clone(), method)),
+        );
+        self
+    }
+
+    /// Add exceptions for requests Rocket would route to routes with the given names, which are
+    /// the names of their handler functions by default. See [`add_route_exceptions`] for more
+    /// informations on how route exceptions work.
+    ///
+    /// [`add_route_exceptions`]: #method.add_route_exceptions
+    pub fn add_named_exceptions(mut self, exceptions: Vec<(String, String, Option<Method>)>) -> Self {
+        self.route_exceptions.extend(
+            exceptions
+                .into_iter()
+                .map(|(name, destination, method)| (RouteRef::Name(name), destination, method)),
+        );
+        self
+    }
+
+    /// Add routes which are exempt from csrf verification, such as webhooks or OAuth callbacks
+    /// called by third parties, wherever they are mounted. Requests Rocket would route to them are
+    /// neither verified nor rerouted, and so won't pass the [`VerifiedCsrf`] guard. Like route
+    /// exceptions, routes are resolved when Rocket ignite, and the route of a request is guessed
+    /// before Rocket routes it, so when routes share a path, the first one by rank decides
+    /// whether requests are exempt, even if it would forward them, see
+    /// [`add_route_exceptions`].
+    ///
+    /// [`VerifiedCsrf`]: struct.VerifiedCsrf.html
+    /// [`add_route_exceptions`]: #method.add_route_exceptions
+    ///
+    /// # Example
+    ///
+    ///  ```rust,no_run
+    /// # #[macro_use] extern crate rocket;
+    /// # extern crate rocket_csrf;
+    /// use rocket_csrf::CsrfFairingBuilder;
+    ///
+    /// #[post("/payment/webhook", data = "<event>")]
+    /// fn payment_webhook(event: String) -> &'static str {
+    ///     "ok"
+    /// }
+    ///
+    /// #[rocket::main]
+    /// async fn main() {
+    ///     let _ = rocket::build()
+    ///         .attach(rocket_csrf::CsrfFairingBuilder::new()
+    ///                 .add_exempt_routes(routes![payment_webhook])
+    ///                 .finalize().unwrap())
+    ///         .mount("/", routes![payment_webhook])
+    ///         //add your routes, other fairings...
+    ///         .launch()
+    ///         .await;
+    /// }
+    /// ```
+    pub fn add_exempt_routes(mut self, routes: Vec<Route>) -> Self {
+        self.exempt_routes.extend(routes.iter().map(RouteRef::from));
+        self
+    }
+
+    /// Add routes, given by name, which are exempt from csrf verification. See
+    /// [`add_exempt_routes`] for more informations on exempt routes.
+    ///
+    /// [`add_exempt_routes`]: #method.add_exempt_routes
+    pub fn add_exempt_route_names(mut self, names: Vec<String>) -> Self {
+        self.exempt_routes.extend(names.into_iter().map(RouteRef::Name));
         self
     }
 
     /// Set the secret key used to generate secure cryptographic tokens. If not set, rocket_csrf
-    /// will attempt to get the secret used by Rocket for it's own private cookies via the
-    /// ROCKET_SECRET_KEY environment variable, or will generate a new one at each restart.
-    /// Having the secret key set (via this or Rocket environment variable) allow tokens to keep
+    /// derive its own key from the `secret_key` of Rocket's configuration (Rocket.toml,
+    /// ROCKET_SECRET_KEY environment variable...) when the Rocket instance ignite. If Rocket
+    /// has no secret key either, a new one is generated at each restart in the debug profile,
+    /// and ignition fails in other profiles.
+    /// Having the secret key set (via this or Rocket configuration) allow tokens to keep
     /// their validity in case of an application restart.
     ///
     /// # Example
@@ -165,15 +403,16 @@ impl CsrfFairingBuilder {
     /// # extern crate rocket;
     /// # extern crate rocket_csrf;
     /// use rocket_csrf::CsrfFairingBuilder;
-    /// # use rocket::Rocket;
     ///
-    /// fn main() {
-    ///     rocket::ignite()
+    /// #[rocket::main]
+    /// async fn main() {
+    ///     let _ = rocket::build()
     ///         .attach(rocket_csrf::CsrfFairingBuilder::new()
     ///                 .set_secret([0;32])//don't do this, use trully secret array instead
     ///                 .finalize().unwrap())
     ///         //add your routes, other fairings...
-    ///         .launch();
+    ///         .launch()
+    ///         .await;
     /// }
     /// ```
     pub fn set_secret(mut self, secret: [u8; 32]) -> Self {
@@ -181,6 +420,68 @@ impl CsrfFairingBuilder {
         self
     }
 
+    /// Set secret keys which were previously used, and are still accepted when verifying tokens.
+    /// New tokens are always generated with the key given to `set_secret`, so rotating a key
+    /// consist of moving the old key here and setting a new one. Tokens issued with a previous
+    /// key are upgraded to the new key the next time a page is served. Keys previously derived
+    /// from Rocket's `secret_key` are given by [`set_previous_secret_keys`] instead. Default is
+    /// no previous key.
+    ///
+    /// [`set_previous_secret_keys`]: #method.set_previous_secret_keys
+    ///
+    /// # Example
+    ///
+    ///  ```rust,no_run
+    /// # extern crate rocket;
+    /// # extern crate rocket_csrf;
+    /// use rocket_csrf::CsrfFairingBuilder;
+    ///
+    /// #[rocket::main]
+    /// async fn main() {
+    ///     let _ = rocket::build()
+    ///         .attach(rocket_csrf::CsrfFairingBuilder::new()
+    ///                 .set_secret([1;32])//don't do this, use trully secret arrays instead
+    ///                 .set_previous_secrets(vec![[0;32]])
+    ///                 .finalize().unwrap())
+    ///         //add your routes, other fairings...
+    ///         .launch()
+    ///         .await;
+    /// }
+    /// ```
+    pub fn set_previous_secrets(mut self, secrets: Vec<[u8; 32]>) -> Self {
+        self.previous_secrets = secrets;
+        self
+    }
+
+    /// Set values Rocket's `secret_key` previously had, from which keys still accepted when
+    /// verifying tokens are derived, like the active key is derived from the current
+    /// `secret_key`. This allows to rotate Rocket's secret key without invalidating tokens, and
+    /// may also be set in the `previous_secret_keys` entry of the `csrf` configuration table,
+    /// with the same formats as `secret_key`. Default is no previous key.
+    ///
+    /// # Example
+    ///
+    ///  ```rust,no_run
+    /// # extern crate rocket;
+    /// # extern crate rocket_csrf;
+    /// use rocket_csrf::CsrfFairingBuilder;
+    ///
+    /// #[rocket::main]
+    /// async fn main() {
+    ///     let _ = rocket::build()
+    ///         .attach(rocket_csrf::CsrfFairingBuilder::new()
+    ///                 .set_previous_secret_keys(vec![vec![0;64]])//the former value of ROCKET_SECRET_KEY
+    ///                 .finalize().unwrap())
+    ///         //add your routes, other fairings...
+    ///         .launch()
+    ///         .await;
+    /// }
+    /// ```
+    pub fn set_previous_secret_keys(mut self, keys: Vec<Vec<u8>>) -> Self {
+        self.previous_secret_keys = keys;
+        self
+    }
+
     /// Set if this should modify response to insert tokens automatically in all forms. If true,
     /// this will insert tokens in all forms it encounter, if false, you will have to add them via
     /// [CsrfFairing](struct.CsrfFairing.html), which you may obtain via request guards.
@@ -193,7 +494,11 @@ impl CsrfFairingBuilder {
     /// Set prefixs for which this will not try to add tokens in forms. This has no effect if
     /// auto_insert is set to false. Not having to parse response on paths witch don't need it may
     /// improve performances, but not that only html documents are parsed, so it's not usefull to
-    /// use it on routes containing only images or stillsheets.
+    /// use it on routes containing only images or stillsheets. Prefixes match whole segments, so
+    /// `/static` match `/static/main.css` but not `/staticfile`, and may contain the same patterns
+    /// as exceptions, see [`set_exceptions`].
+    ///
+    /// [`set_exceptions`]: #method.set_exceptions
     pub fn set_auto_insert_disable_prefix(mut self, auto_insert_prefix: Vec<String>) -> Self {
         self.auto_insert_disable_prefix = auto_insert_prefix;
         self
@@ -207,53 +512,414 @@ impl CsrfFairingBuilder {
         self
     }
 
-    /// Get the fairing from the builder.
-    pub fn finalize(self) -> Result<CsrfFairing, ()> {
-        let secret = self.secret.unwrap_or_else(|| {
-            //use provided secret if one is
-            env::vars()
-                .find(|(key, _)| key == "ROCKET_SECRET_KEY")
-                .and_then(|(_, value)| {
-                    let b64 = BASE64.decode(value.as_bytes());
-                    if let Ok(b64) = b64 {
-                        if b64.len() == 32 {
-                            let mut array = [0; 32];
-                            array.copy_from_slice(&b64);
-                            Some(array)
-                        } else {
-                            None
-                        }
-                    } else {
-                        None
-                    }
-                })//else get secret environment variable
-                .unwrap_or_else(|| {
-                    eprintln!("[rocket_csrf] No secret key was found, you should consider set one to keep token validity across application restart");
-                    let rand = SystemRandom::new();
-                    let mut array = [0;32];
-                    rand.fill(&mut array).unwrap();
-                    array
-                }) //if environment variable is not set, generate a random secret and print a warning
-        });
-
-        let default_target = Path::from(&self.default_target.0);
-        let mut hashmap = HashMap::new();
-        hashmap.insert("uri", "".to_owned());
-        if default_target.map(&hashmap).is_none() {
-            return Err(());
-        } //verify if this path is valid as default path, i.e. it have at most one dynamic part which is <uri>
+    /// Set the name of the cookie in which the secret part of tokens is stored. Default name is
+    /// `csrf`, you may want to change it if your application already use a cookie with this name.
+    pub fn set_cookie_name(mut self, cookie_name: String) -> Self {
+        self.cookie_name = cookie_name;
+        self
+    }
+
+    /// Set the name of the form field containing the token. This is used to find the token in
+    /// both urlencoded and multipart forms, and as the name of inputs inserted into forms when
+    /// auto_insert is enabled. Default name is `csrf-token`.
+    pub fn set_form_field_name(mut self, form_field: String) -> Self {
+        self.form_field = form_field;
+        self
+    }
+
+    /// Set the list of headers in which a token may be sent instead of in the request body. This
+    /// allow protecting requests which are not forms, like json apis or `DELETE` requests sent via
+    /// `fetch()`. Headers are checked in order before the body. Default headers are
+    /// `X-CSRF-Token` and `X-XSRF-Token`, set an empty list to only accept tokens from forms.
+    pub fn set_token_headers(mut self, token_headers: Vec<String>) -> Self {
+        self.token_headers = token_headers;
+        self
+    }
+
+    /// Set if a token readable by javascript should be sent in a cookie with every response, as
+    /// expected by frameworks like Angular or Axios. The cookie is named `XSRF-TOKEN`, and the
+    /// token must be sent back in the `X-XSRF-TOKEN` header, where it is verified against the
+    /// usual csrf cookie. See [`set_double_submit_names`] to use other names.
+    ///
+    /// [`set_double_submit_names`]: #method.set_double_submit_names
+    pub fn set_double_submit(mut self, double_submit: bool) -> Self {
+        self.double_submit = if double_submit {
+            Some(("XSRF-TOKEN".to_owned(), "X-XSRF-TOKEN".to_owned()))
+        } else {
+            None
+        };
+        self
+    }
+
+    /// Enable sending a token readable by javascript in a cookie, as with [`set_double_submit`],
+    /// using a custom cookie name, and a custom header in which it must be sent back.
+    ///
+    /// [`set_double_submit`]: #method.set_double_submit
+    pub fn set_double_submit_names(mut self, cookie_name: String, header_name: String) -> Self {
+        self.double_submit = Some((cookie_name, header_name));
+        self
+    }
+
+    /// Set if the csrf cookie, and the double submit cookie, are only sent over https. Browsers
+    /// ignore such cookies on plain http origins other than `localhost`, so this must be disabled
+    /// to serve an application over plain http, for instance on a local network during
+    /// development. This may also be set in the `secure_cookies` entry of the `csrf`
+    /// configuration table, to only disable it in some profiles. Default is true.
+    pub fn set_secure_cookies(mut self, secure_cookies: bool) -> Self {
+        self.secure_cookies = secure_cookies;
+        self
+    }
+
+    /// Set which verifications a request must pass to not be considered as a csrf violation.
+    /// Default is [`CheckMode::TokenOnly`], see [CheckMode](enum.CheckMode.html) for other modes.
+    ///
+    /// [`CheckMode::TokenOnly`]: enum.CheckMode.html#variant.TokenOnly
+    pub fn set_check_mode(mut self, check_mode: CheckMode) -> Self {
+        self.check_mode = check_mode;
+        self
+    }
+
+    /// Set the list of origins allowed to send requests, when the check mode verify origins. An
+    /// entry can be either a full origin such as `https://example.com:8000`, or only a host such
+    /// as `example.com`, in which case any scheme is accepted. If empty, which is the default,
+    /// only requests whose origin match the `Host` header are allowed.
+    ///
+    /// # Example
+    ///
+    ///  ```rust,no_run
+    /// # extern crate rocket;
+    /// # extern crate rocket_csrf;
+    /// use rocket_csrf::{CheckMode, CsrfFairingBuilder};
+    ///
+    /// #[rocket::main]
+    /// async fn main() {
+    ///     let _ = rocket::build()
+    ///         .attach(rocket_csrf::CsrfFairingBuilder::new()
+    ///                 .set_check_mode(CheckMode::TokenAndOrigin)
+    ///                 .set_allowed_origins(vec!["https://example.com".to_owned()])
+    ///                 .finalize().unwrap())
+    ///         //add your routes, other fairings...
+    ///         .launch()
+    ///         .await;
+    /// }
+    /// ```
+    pub fn set_allowed_origins(mut self, allowed_origins: Vec<String>) -> Self {
+        self.allowed_origins = allowed_origins;
+        self
+    }
+
+    /// Set if requests should be filtered using the `Sec-Fetch-Site` header sent by modern
+    /// browsers. If enabled, requests coming from the same origin, or directly from the user, are
+    /// accepted without further checks, while cross-site requests are rejected, except for the
+    /// allowances set via [`set_fetch_metadata_allow_navigation`] and
+    /// [`set_fetch_metadata_allowed_paths`]. Same-site requests, requests without this header,
+    /// and requests matching an allowance are verified as configured by [`set_check_mode`],
+    /// unless [`set_fetch_metadata_reject_same_site`] is enabled. Default is false.
+    ///
+    /// [`set_fetch_metadata_allow_navigation`]: #method.set_fetch_metadata_allow_navigation
+    /// [`set_fetch_metadata_reject_same_site`]: #method.set_fetch_metadata_reject_same_site
+    /// [`set_fetch_metadata_allowed_paths`]: #method.set_fetch_metadata_allowed_paths
+    /// [`set_check_mode`]: #method.set_check_mode
+    pub fn set_fetch_metadata(mut self, fetch_metadata: bool) -> Self {
+        self.fetch_metadata = fetch_metadata;
+        self
+    }
+
+    /// Set if cross-site top-level navigations, such as a form on another site posting to this
+    /// one, should not be rejected by fetch metadata filtering, but verified by the usual checks
+    /// instead. Default is false.
+    pub fn set_fetch_metadata_allow_navigation(mut self, allow_navigation: bool) -> Self {
+        self.fetch_metadata_allow_navigation = allow_navigation;
+        self
+    }
+
+    /// Set if same-site requests, coming from another subdomain of this site, should be rejected
+    /// by fetch metadata filtering like cross-site ones, instead of being verified by the usual
+    /// checks. Only enable it if no other subdomain is trusted to post to this one. Default is
+    /// false.
+    pub fn set_fetch_metadata_reject_same_site(mut self, reject_same_site: bool) -> Self {
+        self.fetch_metadata_reject_same_site = reject_same_site;
+        self
+    }
+
+    /// Set path prefixes on which cross-site requests should not be rejected by fetch metadata
+    /// filtering, but verified by the usual checks instead. Like other prefixes, they match whole
+    /// segments, so `/api` match `/api/upload` but not `/apifoo`, see
+    /// [`set_auto_insert_disable_prefix`].
+    ///
+    /// [`set_auto_insert_disable_prefix`]: #method.set_auto_insert_disable_prefix
+    pub fn set_fetch_metadata_allowed_paths(mut self, allowed_paths: Vec<String>) -> Self {
+        self.fetch_metadata_allowed_paths = allowed_paths;
+        self
+    }
+
+    /// Set if requests violating csrf protection should be redirected to the default target or
+    /// matching exceptions. If false, requests are still verified, but only routes using the
+    /// [VerifiedCsrf](struct.VerifiedCsrf.html) request guard will reject violations, and
+    /// exceptions are not used. Default is true.
+    pub fn set_enforce_globally(mut self, enforce_globally: bool) -> Self {
+        self.enforce_globally = enforce_globally;
+        self
+    }
+
+    /// Set a status with which requests violating csrf protection are rejected, instead of being
+    /// redirected to the default target. Rejected requests keep their method and are handled by
+    /// the Rocket catcher registered for this status, which can get the reason of the rejection
+    /// via [`CsrfViolation::of`]. Exceptions are still applied before rejecting a request.
+    ///
+    /// [`CsrfViolation::of`]: enum.CsrfViolation.html#method.of
+    ///
+    /// # Example
+    ///
+    ///  ```rust,no_run
+    /// # #[macro_use] extern crate rocket;
+    /// # extern crate rocket_csrf;
+    /// use rocket::http::Status;
+    /// use rocket::Request;
+    /// use rocket_csrf::{CsrfFairingBuilder, CsrfViolation};
+    ///
+    /// #[catch(403)]
+    /// fn forbidden(request: &Request) -> String {
+    ///     match (CsrfViolation::of(request), CsrfViolation::uri_of(request)) {
+    ///         (Some(violation), Some(uri)) => format!("csrf violation on {}: {}", uri, violation),
+    ///         _ => "forbidden".to_owned(),
+    ///     }
+    /// }
+    ///
+    /// #[rocket::main]
+    /// async fn main() {
+    ///     let _ = rocket::build()
+    ///         .attach(rocket_csrf::CsrfFairingBuilder::new()
+    ///                 .set_reject_status(Status::Forbidden)
+    ///                 .finalize().unwrap())
+    ///         .register("/", catchers![forbidden])
+    ///         //add your routes, other fairings...
+    ///         .launch()
+    ///         .await;
+    /// }
+    /// ```
+    pub fn set_reject_status(mut self, status: Status) -> Self {
+        self.reject_status = Some(status);
+        self
+    }
+
+    /// Set a [SessionBinder](trait.SessionBinder.html) giving the session of requests, to which
+    /// tokens are bound. Tokens issued in a session are then rejected in other sessions, and
+    /// after the session ends, so that a token obtained before a user log in can't be used once
+    /// they are logged in. Default is to not bind tokens to sessions.
+    pub fn set_session_binder<B: SessionBinder>(mut self, binder: B) -> Self {
+        self.session_binder = Some(Arc::new(binder));
+        self
+    }
+
+    /// Set if tokens inserted in forms should be bound to the action and method of their form. A
+    /// token inserted in a form is then only valid for requests to the path this form is sent
+    /// to, with its method (or the one set by its `_method` field), so a token leaked from a form
+    /// can't be used to send another one. This has no effect if auto_insert is set to false.
+    /// Default is false.
+    ///
+    /// Tokens obtained via the [CsrfToken](struct.CsrfToken.html) request guard are not scoped,
+    /// and stay valid for any request, so a page using this guard leaks a token which can send
+    /// any form. Use [`set_require_scoped_form_tokens`] to only accept them in headers.
+    ///
+    /// [`set_require_scoped_form_tokens`]: #method.set_require_scoped_form_tokens
+    pub fn set_scoped_tokens(mut self, scoped_tokens: bool) -> Self {
+        self.scoped_tokens = scoped_tokens;
+        self
+    }
+
+    /// Set if tokens sent in the body of a request must be scoped, when scoped tokens are
+    /// enabled. Forms can then only be sent with the token inserted in them, while unscoped
+    /// tokens, obtained via the [CsrfToken](struct.CsrfToken.html) request guard, are only
+    /// accepted in headers, see [`set_token_headers`]. This has no effect unless
+    /// [`set_scoped_tokens`] is set. Default is false.
+    ///
+    /// [`set_token_headers`]: #method.set_token_headers
+    /// [`set_scoped_tokens`]: #method.set_scoped_tokens
+    pub fn set_require_scoped_form_tokens(mut self, require_scoped_form_tokens: bool) -> Self {
+        self.require_scoped_form_tokens = require_scoped_form_tokens;
+        self
+    }
+
+    /// Set if tokens can only be used once. Tokens used by a request are then remembered until
+    /// they expire, and requests using them again are violations. As every form of a page
+    /// receive the same token, unless [`set_scoped_tokens`] is set, only one of them can be
+    /// sent, and clients sending tokens in headers must get a new token for each request.
+    /// Used tokens are kept in memory, see [`set_replay_store`] to use another store. With
+    /// [`TokenMode::Stored`], each token obtained from the request guard is a new stored id,
+    /// which is removed from the token store once used, and scoped tokens of a page, which share
+    /// the stored id of the page, are remembered as used one by one. Sessions then keep their 64
+    /// most recent stored tokens, so a page left open while 64 others are loaded must be reloaded. This can't be combined
+    /// with [`set_masked_tokens`]. Default is false.
+    ///
+    /// [`TokenMode::Stored`]: enum.TokenMode.html#variant.Stored
+    /// [`set_scoped_tokens`]: #method.set_scoped_tokens
+    /// [`set_replay_store`]: #method.set_replay_store
+    /// [`set_masked_tokens`]: #method.set_masked_tokens
+    pub fn set_one_time_tokens(mut self, one_time_tokens: bool) -> Self {
+        self.one_time_tokens = one_time_tokens;
+        self
+    }
+
+    /// Set the [ReplayStore](trait.ReplayStore.html) in which used tokens are remembered, and
+    /// enable one-time tokens, see [`set_one_time_tokens`].
+    ///
+    /// [`set_one_time_tokens`]: #method.set_one_time_tokens
+    pub fn set_replay_store<S: ReplayStore>(mut self, store: S) -> Self {
+        self.one_time_tokens = true;
+        self.replay_store = Some(Arc::new(store));
+        self
+    }
+
+    /// Set how tokens are issued and verified. Default is [`TokenMode::Cookie`], see
+    /// [TokenMode](enum.TokenMode.html) for other modes. [`TokenMode::Stateless`] and
+    /// [`TokenMode::Stored`] require a session binder, set via [`set_session_binder`]. Stored
+    /// tokens are kept in memory, see [`set_token_store`] to use another store.
+    ///
+    /// [`TokenMode::Cookie`]: enum.TokenMode.html#variant.Cookie
+    /// [`TokenMode::Stateless`]: enum.TokenMode.html#variant.Stateless
+    /// [`TokenMode::Stored`]: enum.TokenMode.html#variant.Stored
+    /// [`set_session_binder`]: #method.set_session_binder
+    /// [`set_token_store`]: #method.set_token_store
+    ///
+    /// # Example
+    ///
+    ///  ```rust,no_run
+    /// # extern crate rocket;
+    /// # extern crate rocket_csrf;
+    /// use rocket::Request;
+    /// use rocket_csrf::{CsrfFairingBuilder, TokenMode};
+    ///
+    /// #[rocket::main]
+    /// async fn main() {
+    ///     let _ = rocket::build()
+    ///         .attach(rocket_csrf::CsrfFairingBuilder::new()
+    ///                 .set_token_mode(TokenMode::Stateless)
+    ///                 .set_session_binder(|request: &Request<'_>| {
+    ///                     request.headers().get_one("X-Session-Id").map(|id| id.as_bytes().to_vec())
+    ///                 })
+    ///                 .finalize().unwrap())
+    ///         //add your routes, other fairings...
+    ///         .launch()
+    ///         .await;
+    /// }
+    /// ```
+    pub fn set_token_mode(mut self, token_mode: TokenMode) -> Self {
+        self.token_mode = token_mode;
+        self
+    }
+
+    /// Set the [TokenStore](trait.TokenStore.html) in which tokens are kept, and use
+    /// [`TokenMode::Stored`]. Pass an `Arc` of the store to keep a handle on it, to revoke or
+    /// audit tokens of a session.
+    ///
+    /// [`TokenMode::Stored`]: enum.TokenMode.html#variant.Stored
+    pub fn set_token_store<S: TokenStore>(mut self, store: S) -> Self {
+        self.token_mode = TokenMode::Stored;
+        self.token_store = Some(Arc::new(store));
+        self
+    }
+
+    /// Set if tokens should be masked instead of encrypted, when using [`TokenMode::Cookie`]. A
+    /// masked token is a random one-time pad followed by the secret of the csrf cookie XORed with
+    /// this pad, like tokens of Django or Rails. Like encrypted tokens, they differ at each
+    /// request, so compressed pages don't leak them (BREACH), but they are 86 characters long
+    /// instead of 139. Encrypted tokens stay accepted while masking is enabled, so enabling it
+    /// doesn't invalidate tokens already issued, but masked tokens are rejected once it is
+    /// disabled. As anyone knowing a masked token can mask its secret again with another pad,
+    /// masked tokens can't be one-time tokens, see [`set_one_time_tokens`]. Default is false.
+    ///
+    /// [`set_one_time_tokens`]: #method.set_one_time_tokens
+    /// [`TokenMode::Cookie`]: enum.TokenMode.html#variant.Cookie
+    pub fn set_masked_tokens(mut self, masked_tokens: bool) -> Self {
+        self.masked_tokens = masked_tokens;
+        self
+    }
+
+    /// Get the fairing from the builder, verifying paths of the default target and exceptions are
+    /// valid.
+    pub fn finalize(self) -> Result<CsrfFairing, ConfigError> {
+        if self.token_mode != TokenMode::Cookie && self.session_binder.is_none() {
+            return Err(ConfigError::MissingSessionBinder);
+        }
+        if self.token_mode == TokenMode::Cookie && self.masked_tokens && self.one_time_tokens {
+            return Err(ConfigError::MaskedOneTimeTokens);
+        } //pads are chosen by clients, so they can't identify used tokens
+        let default_target = parse_default_target(&self.default_target.0)?;
+        let exceptions = self
+            .exceptions
+            .iter()
+            .map(|(source_method, src, dst, method)| {
+                parse_exception(src, dst, *method).map(|(src, dst, method)| (*source_method, src, dst, method))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let auto_insert_disable_prefix = self
+            .auto_insert_disable_prefix
+            .iter()
+            .map(|prefix| parse_prefix(prefix))
+            .collect::<Result<Vec<_>, _>>()?;
+        let fetch_metadata_allowed_paths = self
+            .fetch_metadata_allowed_paths
+            .iter()
+            .map(|prefix| parse_prefix(prefix).map(|prefix| (prefix, ())))
+            .collect::<Result<Vec<_>, _>>()?;
+        let fetch_metadata_allowed_paths =
+            PathSet::new(fetch_metadata_allowed_paths).map_err(|_| ConfigError::TooManyPatterns)?;
+        for (_, destination, _) in &self.route_exceptions {
+            parse_path(destination)?;
+        } //routes are only known at ignite, but destinations can already be parsed
+        let mut token_headers = self.token_headers;
+        if let Some((_, header)) = &self.double_submit {
+            if !token_headers.iter().any(|h| h.eq_ignore_ascii_case(header)) {
+                token_headers.push(header.clone());
+            }
+        } //the double submit header must be checked, even if it was not explicitly listed
+
         Ok(CsrfFairing {
             duration: self.duration,
             default_target: (default_target, self.default_target.1),
-            exceptions: self
-                .exceptions
-                .iter()
-                .map(|(a, b, m)| (Path::from(a), Path::from(b), *m))//TODO verify if source and target are compatible
-                .collect(),
-            secret,
+            protected_methods: self.protected_methods,
+            exceptions,
+            route_exceptions: self.route_exceptions,
+            exempt_routes: self.exempt_routes,
+            secret: self.secret,
+            previous_secrets: self.previous_secrets,
+            previous_secret_keys: self.previous_secret_keys,
             auto_insert: self.auto_insert,
-            auto_insert_disable_prefix: self.auto_insert_disable_prefix,
+            auto_insert_disable_prefix,
             auto_insert_max_size: self.auto_insert_max_size,
+            form_field_multipart: format!(
+                "Content-Disposition: form-data; name=\"{}\"",
+                self.form_field
+            ).into_bytes(),
+            cookie_name: self.cookie_name,
+            form_field: self.form_field,
+            token_headers,
+            double_submit_cookie: self.double_submit.map(|(cookie, _)| cookie),
+            secure_cookies: self.secure_cookies,
+            check_mode: self.check_mode,
+            allowed_origins: self.allowed_origins,
+            fetch_metadata: self.fetch_metadata,
+            fetch_metadata_allow_navigation: self.fetch_metadata_allow_navigation,
+            fetch_metadata_reject_same_site: self.fetch_metadata_reject_same_site,
+            fetch_metadata_allowed_paths,
+            enforce_globally: self.enforce_globally,
+            reject_status: self.reject_status,
+            session_binder: self.session_binder,
+            scoped_tokens: self.scoped_tokens,
+            require_scoped_form_tokens: self.require_scoped_form_tokens,
+            token_mode: self.token_mode,
+            replay_store: if self.one_time_tokens {
+                Some(self.replay_store.unwrap_or_else(|| Arc::new(MemoryReplayStore::default())))
+            } else {
+                None
+            },
+            token_store: if self.token_mode == TokenMode::Stored {
+                Some(self.token_store.unwrap_or_else(|| Arc::new(MemoryTokenStore::new())))
+            } else {
+                None
+            },
+            masked_tokens: self.masked_tokens,
         })
     }
 }
@@ -267,186 +933,766 @@ impl Default for CsrfFairingBuilder {
 /// Fairing to protect against Csrf attacks.
 ///
 /// The `CsrfFairing` type protect a Rocket instance against Csrf attack by requesting mendatory
-/// token on any POST, PUT, DELETE or PATCH request.
+/// token on any POST, PUT, DELETE or PATCH request by default, see `set_protected_methods`.
 /// This is created via a [CsrfFairingBuilder](struct.CsrfFairingBuilder.html), and implement nothing else than the `Fairing` trait.
 ///
 /// [`CsrfFairingBuilder`]: /rocket_csrf/struct.CsrfFairing.html
 pub struct CsrfFairing {
     duration: u64,
     default_target: (Path, Method),
-    exceptions: Vec<(Path, Path, Option<Method>)>,
-    secret: [u8; 32],
+    protected_methods: Vec<Method>,
+    exceptions: Vec<(Option<Method>, Path, Path, Option<Method>)>,
+    route_exceptions: Vec<(RouteRef, String, Option<Method>)>,
+    exempt_routes: Vec<RouteRef>,
+    secret: Option<[u8; 32]>,
+    previous_secrets: Vec<[u8; 32]>,
+    previous_secret_keys: Vec<Vec<u8>>,
     auto_insert: bool,
-    auto_insert_disable_prefix: Vec<String>,
+    auto_insert_disable_prefix: Vec<Path>,
     auto_insert_max_size: u64,
+    cookie_name: String,
+    form_field: String,
+    form_field_multipart: Vec<u8>,
+    token_headers: Vec<String>,
+    double_submit_cookie: Option<String>,
+    secure_cookies: bool,
+    check_mode: CheckMode,
+    allowed_origins: Vec<String>,
+    fetch_metadata: bool,
+    fetch_metadata_allow_navigation: bool,
+    fetch_metadata_reject_same_site: bool,
+    fetch_metadata_allowed_paths: PathSet<()>,
+    enforce_globally: bool,
+    reject_status: Option<Status>,
+    session_binder: Option<Arc<dyn SessionBinder>>,
+    scoped_tokens: bool,
+    require_scoped_form_tokens: bool,
+    token_mode: TokenMode,
+    replay_store: Option<Arc<dyn ReplayStore>>, //only set if tokens are one-time
+    token_store: Option<Arc<dyn TokenStore>>, //only set if tokens are stored
+    masked_tokens: bool,
 }
 
-impl Fairing for CsrfFairing {
-    fn info(&self) -> Info {
-        if self.auto_insert {
-            Info {
-                name: "CSRF protection",
-                kind: Kind::Attach | Kind::Request | Kind::Response,
-            }
-        } else {
-            Info {
-                name: "CSRF protection",
-                kind: Kind::Attach | Kind::Request,
-            }
-        }
+/// Handler failing with a given status, to which violations are rerouted so they reach catchers.
+#[derive(Clone)]
+struct RejectHandler(Status);
+
+#[rocket::async_trait]
+impl Handler for RejectHandler {
+    async fn handle<'r>(&self, _request: &'r Request<'_>, _data: Data<'r>) -> route::Outcome<'r> {
+        route::Outcome::Error(self.0)
     }
+}
+
+/// Get the scope of tokens valid for a request with a given method and path.
+fn token_scope(method: Method, path: &str) -> Vec<u8> {
+    format!("{} {}", method.as_str(), normalize(path)).into_bytes()
+}
 
-    fn on_attach(&self, rocket: Rocket) -> Result<Rocket, Rocket> {
-        Ok(rocket.manage((CsrfProtection::from_key(self.secret), self.duration))) //add the Csrf engine to Rocket's managed state
+/// Read Rocket's `secret_key`, accepting the same formats as Rocket does. An unset or zero key is
+/// reported as missing.
+fn configured_secret(figment: &Figment) -> Result<Option<Vec<u8>>, String> {
+    match figment.find_value("secret_key") {
+        Ok(value) => parse_secret(&value, "secret_key"),
+        Err(_) => Ok(None),
     }
+}
 
-    fn on_request(&self, request: &mut Request, data: &Data) {
-        match request.method() {
-            Get | Head | Connect | Options => {
-                return;
-            }
-            _ => {}
-        };
+/// Parse a secret key given in any of the formats Rocket accepts for its `secret_key`.
+fn parse_secret(value: &Value, name: &str) -> Result<Option<Vec<u8>>, String> {
+    let material = if let Some(key) = value.as_str() {
+        match key.len() {
+            44 | 88 => BASE64.decode(key.as_bytes()).ok(),
+            64 => HEXLOWER_PERMISSIVE.decode(key.as_bytes()).ok(),
+            _ => None,
+        }
+    } else {
+        value.as_array().and_then(|bytes| {
+            bytes
+                .iter()
+                .map(|b| b.to_u128().and_then(|b| u8::try_from(b).ok()))
+                .collect()
+        })
+    }
+    .ok_or_else(|| format!("`{}` must be a base64 or hex string, or an array of bytes", name))?;
+
+    if material.len() < 32 {
+        Err(format!("`{}` must be at least 256 bits long", name))
+    } else if material.iter().all(|&b| b == 0) {
+        Ok(None)
+    } else {
+        Ok(Some(material))
+    }
+}
 
-        {
-            let cookies = request.cookies();
-            if cookies.iter().count() == 0 {
-                return;
+/// Verifications a request must pass to be accepted.
+///
+/// The `CheckMode` type select if requests are verified using csrf tokens, their `Origin` header
+/// (or `Referer` header if there is no `Origin`), or both. Origins are compared against the
+/// list set by [`set_allowed_origins`]. An `Origin` of `null`, sent by sandboxed frames or after
+/// some redirections, never pass the origin check.
+///
+/// [`set_allowed_origins`]: struct.CsrfFairingBuilder.html#method.set_allowed_origins
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckMode {
+    /// Only verify the csrf token, this is the default.
+    TokenOnly,
+    /// Only verify the origin, allowing to protect endpoints which can't send tokens.
+    OriginOnly,
+    /// Verify both the token and the origin.
+    TokenAndOrigin,
+    /// Accept requests having either a valid token or an allowed origin.
+    Either,
+}
+
+/// How tokens are issued and verified.
+///
+/// The `TokenMode` type select if tokens are verified against a secret stored in an encrypted
+/// cookie, only against the session of the request, given by a
+/// [SessionBinder](trait.SessionBinder.html), which allow to protect applications served behind
+/// caches or proxies dropping unknown cookies, or against tokens kept server-side for this
+/// session, which can then be revoked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenMode {
+    /// Tokens are pairs of an encrypted token and an encrypted cookie, this is the default.
+    Cookie,
+    /// Tokens are signed along with the session they are issued for and their expiration date,
+    /// no cookie is used. This requires a session binder, and requests without session are
+    /// not verified.
+    Stateless,
+    /// Tokens are random ids kept in a [TokenStore](trait.TokenStore.html) for the session they
+    /// are issued for, no cookie is used. This requires a session binder, and requests without
+    /// session are not verified.
+    Stored,
+}
+
+/// State shared by the fairing with request guards, via Rocket's managed state.
+pub struct CsrfState {
+    pub engine: CsrfProtection,
+    pub duration: u64,
+    pub cookie_name: String,
+    pub double_submit_cookie: Option<String>,
+    pub secure_cookies: bool,
+    pub default_target: (Path, Method),
+    pub protected_methods: Vec<Method>,
+    pub exceptions: PathSet<(Option<Method>, Path, Option<Method>)>,
+    pub routes: RouteTable,
+    pub auto_insert: bool,
+    pub auto_insert_disable_prefix: PathSet<()>,
+    pub auto_insert_max_size: u64,
+    pub session_binder: Option<Arc<dyn SessionBinder>>,
+    pub token_mode: TokenMode,
+    pub token_store: Option<Arc<dyn TokenStore>>,
+    pub masked_tokens: bool,
+    pub one_time_tokens: bool,
+}
+
+impl CsrfState {
+    /// Get the session of a request, to which its tokens are bound, if there is one.
+    pub fn session_id(&self, request: &Request<'_>) -> Option<Vec<u8>> {
+        self.session_binder
+            .as_ref()
+            .and_then(|binder| binder.session_id(request))
+    }
+}
+
+impl CsrfFairing {
+    fn state(&self, engine: CsrfProtection, config: CsrfConfig, mounted: &[&Route]) -> Result<CsrfState, ConfigError> {
+        //merge settings of the fairing with those read from Rocket's configuration
+        let default_target = match config.default_target {
+            Some(target) => (parse_default_target(&target.path)?, target.method),
+            None => self.default_target.clone(),
+        };
+        let mut exceptions = self.exceptions.clone();
+        for exception in &config.exceptions {
+            let (src, dst, method) = parse_exception(&exception.source, &exception.destination, exception.method)?;
+            exceptions.push((exception.source_method, src, dst, method));
+        }
+        let mut exempt_routes = self.exempt_routes.clone();
+        exempt_routes.extend(config.exempt_routes.into_iter().map(RouteRef::Name));
+        let mut routes = RouteTable::new();
+        if !self.route_exceptions.is_empty() || !exempt_routes.is_empty() {
+            let references = self.route_exceptions.iter().map(|(reference, _, _)| reference);
+            if let Some(reference) = references
+                .chain(&exempt_routes)
+                .find(|reference| !mounted.iter().any(|route| reference.resolves_to(route)))
+            {
+                return Err(ConfigError::UnknownRoute {
+                    route: reference.to_string(),
+                });
+            }
+            for route in mounted {
+                let exception = match self.route_exceptions.iter().find(|(reference, _, _)| reference.resolves_to(route)) {
+                    Some((_, destination, method)) => {
+                        Some(parse_exception(route.uri.origin.path().as_str(), destination, *method)?)
+                    }
+                    None => None,
+                };
+                let exempt = exempt_routes.iter().any(|reference| reference.resolves_to(route));
+                routes.add(route, exception, exempt);
             }
+        } //resolve route exceptions and exemptions, keeping all routes as they may take precedence over others
+        let mut auto_insert_disable_prefix = self.auto_insert_disable_prefix.clone();
+        for prefix in &config.auto_insert_disable_prefix {
+            auto_insert_disable_prefix.push(parse_prefix(prefix)?);
         }
+        let exceptions = exceptions
+            .into_iter()
+            .map(|(source_method, src, dst, method)| (src, (source_method, dst, method)))
+            .collect();
+        let prefixes = auto_insert_disable_prefix.into_iter().map(|prefix| (prefix, ())).collect();
+        Ok(CsrfState {
+            engine,
+            duration: config.duration.unwrap_or(self.duration),
+            cookie_name: self.cookie_name.clone(),
+            double_submit_cookie: self.double_submit_cookie.clone(),
+            secure_cookies: config.secure_cookies.unwrap_or(self.secure_cookies),
+            default_target,
+            protected_methods: config.protected_methods.unwrap_or_else(|| self.protected_methods.clone()),
+            exceptions: PathSet::new(exceptions).map_err(|_| ConfigError::TooManyPatterns)?,
+            routes,
+            auto_insert: config.auto_insert.unwrap_or(self.auto_insert),
+            auto_insert_disable_prefix: PathSet::new(prefixes).map_err(|_| ConfigError::TooManyPatterns)?,
+            auto_insert_max_size: config.auto_insert_max_size.unwrap_or(self.auto_insert_max_size),
+            session_binder: self.session_binder.clone(),
+            token_mode: self.token_mode,
+            token_store: self.token_store.clone(),
+            masked_tokens: self.masked_tokens,
+            one_time_tokens: self.replay_store.is_some(),
+        })
+    }
 
-        let (csrf_engine, _) = request
-            .guard::<State<(CsrfProtection, u64)>>()
-            .unwrap()
-            .inner();
+    async fn verify(&self, state: &CsrfState, request: &Request<'_>, data: &mut Data<'_>) -> Result<(), CsrfViolation> {
+        if !state.protected_methods.contains(&request.method()) {
+            return Ok(());
+        }
+
+        let has_session = match state.token_mode {
+            TokenMode::Cookie => request.cookies().iter().count() != 0,
+            TokenMode::Stateless | TokenMode::Stored => state.session_id(request).is_some(),
+        }; //cookieless tokens rely on the session binder, as sessions may not be held in cookies
+        if !has_session {
+            return Ok(());
+        }
+
+        match self.verify_fetch_metadata(request) {
+            Some(verdict) => verdict,
+            None => match self.check_mode {
+                CheckMode::TokenOnly => self.verify_token(request, data).await,
+                CheckMode::OriginOnly => self.verify_origin(request),
+                CheckMode::TokenAndOrigin => {
+                    self.verify_origin(request)?;
+                    self.verify_token(request, data).await
+                }
+                CheckMode::Either => match self.verify_origin(request) {
+                    Ok(()) => Ok(()),
+                    Err(_) => self.verify_token(request, data).await,
+                },
+            }, //fetch metadata were not conclusive, use usual checks
+        }
+    }
+
+    async fn verify_token(&self, request: &Request<'_>, data: &mut Data<'_>) -> Result<(), CsrfViolation> {
+        let state = request.rocket().state::<CsrfState>().unwrap();
+        let (id, ttl) = match state.token_mode {
+            TokenMode::Cookie => self.verify_token_pair(state, request, data).await?,
+            TokenMode::Stateless => match state.session_id(request) {
+                Some(session) => self.verify_signed_token(state, &session, request, data).await?,
+                None => return Ok(()), //without session, there is nothing to protect
+            },
+            TokenMode::Stored => {
+                return match state.session_id(request) {
+                    Some(session) => self.verify_stored_token(state, &session, request, data).await,
+                    None => Ok(()),
+                };
+            } //stored tokens are consumed by removing them from the token store
+        };
 
-        let mut cookie = request
+        match &self.replay_store {
+            Some(store) if !store.consume(&id, ttl).await => Err(CsrfViolation::ReusedToken),
+            _ => Ok(()),
+        } //a valid one-time token is consumed, and rejected if it already was
+    }
+
+    async fn verify_token_pair(
+        &self,
+        state: &CsrfState,
+        request: &Request<'_>,
+        data: &mut Data<'_>,
+    ) -> Result<(Vec<u8>, u64), CsrfViolation> {
+        //verify the token against the csrf cookie, giving its id and how long it stays valid
+        let csrf_engine = &state.engine;
+        let session = state.session_id(request).unwrap_or_default();
+
+        let cookie = request
             .cookies()
-            .get(CSRF_COOKIE_NAME)
-            .and_then(|cookie| BASE64URL_NOPAD.decode(cookie.value().as_bytes()).ok());
-        let cookie = cookie.as_mut().and_then(|c| csrf_engine.parse_cookie(&mut *c).ok()); //get and parse Csrf cookie
+            .get(&self.cookie_name)
+            .ok_or(CsrfViolation::MissingCookie)?;
+        let mut cookie = BASE64URL_NOPAD
+            .decode(cookie.value().as_bytes())
+            .map_err(|_| CsrfViolation::UndecodableCookie)?;
+        let cookie = csrf_engine
+            .parse_cookie(&mut cookie, &session)
+            .map_err(|_| CsrfViolation::InvalidCookie)?; //get and parse Csrf cookie
+
+        let (mut token, unscoped_allowed) = self.request_token(request, data).await?;
+        let mut scoped = token.clone();
+        let scoped = if self.scoped_tokens {
+            let scope = token_scope(request.method(), request.uri().path().as_str());
+            csrf_engine.parse_token(&mut scoped, &session, &scope, false).ok()
+        } else {
+            None
+        }; //tokens inserted in forms may be bound to the request they were made for
+        let token = match scoped {
+            Some(token) => token,
+            None if unscoped_allowed => csrf_engine
+                .parse_token(&mut token, &session, &[], self.masked_tokens)
+                .map_err(|_| CsrfViolation::InvalidToken)?,
+            None => return Err(CsrfViolation::InvalidToken),
+        };
+
+        csrf_engine
+            .verify_token_pair(&token, &cookie)
+            .map_err(|err| match err {
+                CsrfError::Expired => CsrfViolation::ExpiredCookie,
+                _ => CsrfViolation::TokenMismatch,
+            })?; //we got both token and cookie, verify they match each other
+        match token.time_left() {
+            Some(0) => Err(CsrfViolation::ExpiredToken),
+            Some(ttl) => Ok((token.id().to_vec(), ttl)),
+            None => Ok((token.id().to_vec(), cookie.time_left())),
+        } //cookies are refreshed on each page, tokens must expire on their own to be remembered until then
+    }
+
+    async fn verify_signed_token(
+        &self,
+        state: &CsrfState,
+        session: &[u8],
+        request: &Request<'_>,
+        data: &mut Data<'_>,
+    ) -> Result<(Vec<u8>, u64), CsrfViolation> {
+        //verify a cookieless token was signed for this session, giving its id and how long it stays valid
+        let csrf_engine = &state.engine;
+        let (token, unscoped_allowed) = self.request_token(request, data).await?;
+        let scoped = if self.scoped_tokens {
+            let scope = token_scope(request.method(), request.uri().path().as_str());
+            csrf_engine.verify_signed_token(&token, session, &scope)
+        } else {
+            Err(CsrfError::ValidationError)
+        };
+        let token = match scoped {
+            Err(CsrfError::ValidationError) if unscoped_allowed => csrf_engine.verify_signed_token(&token, session, &[]),
+            verdict => verdict,
+        }
+        .map_err(|err| match err {
+            CsrfError::Expired => CsrfViolation::ExpiredToken,
+            _ => CsrfViolation::InvalidToken,
+        })?;
+        Ok((token.id().to_vec(), token.time_left()))
+    }
+
+    async fn verify_stored_token(
+        &self,
+        state: &CsrfState,
+        session: &[u8],
+        request: &Request<'_>,
+        data: &mut Data<'_>,
+    ) -> Result<(), CsrfViolation> {
+        //verify the token is stored for this session, and consume it if tokens are one-time
+        let csrf_engine = &state.engine;
+        let store = state.token_store.as_ref().ok_or(CsrfViolation::InvalidToken)?;
+        let (token, unscoped_allowed) = self.request_token(request, data).await?;
+        let scoped = if self.scoped_tokens {
+            let scope = token_scope(request.method(), request.uri().path().as_str());
+            csrf_engine.parse_stored_token(&token, session, &scope).ok()
+        } else {
+            None
+        };
+        let (id, scoped_id) = match scoped {
+            Some((id, scoped_id)) => (id, Some(scoped_id)),
+            None if unscoped_allowed => csrf_engine
+                .parse_stored_token(&token, session, &[])
+                .map(|(id, _)| (id, None))
+                .map_err(|_| CsrfViolation::InvalidToken)?,
+            None => return Err(CsrfViolation::InvalidToken),
+        };
+
+        let expires = store
+            .expiration(session, &id)
+            .await
+            .ok()
+            .flatten()
+            .ok_or(CsrfViolation::InvalidToken)?; //unknown, revoked, or store unavailable
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        if expires <= now {
+            return Err(CsrfViolation::ExpiredToken);
+        }
+        match (&self.replay_store, scoped_id) {
+            (Some(replay_store), Some(scoped_id)) if !replay_store.consume(&scoped_id, expires - now).await => {
+                Err(CsrfViolation::ReusedToken)
+            }
+            (Some(_), None) if !store.remove(session, &id).await.unwrap_or(false) => Err(CsrfViolation::ReusedToken),
+            _ => Ok(()),
+        } //forms of a page share the stored id, so their scoped tokens are consumed via the replay store
+    }
+
+    async fn request_token(&self, request: &Request<'_>, data: &mut Data<'_>) -> Result<(Vec<u8>, bool), CsrfViolation> {
+        //get the token sent in a header or in the body, decode it, and tell if it may be unscoped
+        let header_token = self
+            .token_headers
+            .iter()
+            .find_map(|name| request.headers().get_one(name));
+        let token = if let Some(token) = header_token {
+            Some(token.trim().as_bytes())
+        } else {
+            let peek = data.peek(512).await; //Rocket won't let us peek at more than 512 bytes
+            self.body_token(request, peek)
+        }.ok_or(CsrfViolation::MissingToken)?;
+        let unscoped_allowed = header_token.is_some() || !(self.scoped_tokens && self.require_scoped_form_tokens);
+        let token = BASE64URL_NOPAD
+            .decode(token)
+            .map_err(|_| CsrfViolation::UndecodableToken)?;
+        Ok((token, unscoped_allowed))
+    }
+
+    fn verify_origin(&self, request: &Request<'_>) -> Result<(), CsrfViolation> {
+        let headers = request.headers();
+        let source = headers
+            .get_one("Origin")
+            .or_else(|| headers.get_one("Referer")); //fallback to the Referer if Origin is absent, a `null` origin is never parsed
+        let (scheme, host) = match source.and_then(parse_origin) {
+            Some(origin) => origin,
+            None => return Err(CsrfViolation::InvalidOrigin),
+        };
+        let allowed = if self.allowed_origins.is_empty() {
+            //without allowed origins, only accept requests from the same host
+            headers
+                .get_one("Host")
+                .map(|target| target.eq_ignore_ascii_case(host))
+                .unwrap_or(false)
+        } else {
+            self.allowed_origins
+                .iter()
+                .any(|allowed| match parse_origin(allowed) {
+                    Some((allowed_scheme, allowed_host)) => {
+                        allowed_scheme.eq_ignore_ascii_case(scheme)
+                            && allowed_host.eq_ignore_ascii_case(host)
+                    }
+                    None => allowed.eq_ignore_ascii_case(host),
+                })
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(CsrfViolation::InvalidOrigin)
+        }
+    }
+
+    fn verify_fetch_metadata(&self, request: &Request<'_>) -> Option<Result<(), CsrfViolation>> {
+        //give a verdict based on Sec-Fetch-* headers, or None to fallback to other checks
+        if !self.fetch_metadata {
+            return None;
+        }
+        let headers = request.headers();
+        let site = headers.get_one("Sec-Fetch-Site")?; //old browsers don't send these headers
+        if site.eq_ignore_ascii_case("same-origin") || site.eq_ignore_ascii_case("none") {
+            return Some(Ok(()));
+        } //request made by our own pages, or directly by the user
+        if site.eq_ignore_ascii_case("same-site") && !self.fetch_metadata_reject_same_site {
+            return None;
+        } //another subdomain, let origin and token checks decide
+
+        if self.fetch_metadata_allowed_paths.is_match(&request.uri().to_string()) {
+            return None;
+        }
+        let navigation = headers
+            .get_one("Sec-Fetch-Mode")
+            .map(|mode| mode.eq_ignore_ascii_case("navigate"))
+            .unwrap_or(false)
+            && headers
+                .get_one("Sec-Fetch-Dest")
+                .map(|dest| dest.eq_ignore_ascii_case("document"))
+                .unwrap_or(true);
+        if navigation && self.fetch_metadata_allow_navigation {
+            return None;
+        }
+        Some(Err(CsrfViolation::CrossSite)) //cross-site or same-site request, reject it
+    }
 
-        let mut token = if request
+    fn body_token<'a>(&self, request: &Request<'_>, peek: &'a [u8]) -> Option<&'a [u8]> {
+        //search for the token in the begining of an urlencoded or multipart body
+        if request
             .content_type()
             .map(|c| c.media_type())
             .filter(|m| m.top() == "multipart" && m.sub() == "form-data")
             .is_some()
         {
-            data.peek().split(|&c| c==0x0A || c==0x0D)//0x0A=='\n', 0x0D=='\r'
+            peek.split(|&c| c==0x0A || c==0x0D)//0x0A=='\n', 0x0D=='\r'
                 .filter(|l| !l.is_empty())
-                .skip_while(|&l| l != CSRF_FORM_FIELD_MULTIPART && l != &CSRF_FORM_FIELD_MULTIPART[..CSRF_FORM_FIELD_MULTIPART.len()-2])
+                .skip_while(|&l| l != &self.form_field_multipart[..] && l != &self.form_field_multipart[..self.form_field_multipart.len()-2])
                 .skip(1)
                 .map(|token| token.split(|&c| c==10 || c==13).next())
                 .next().unwrap_or(None)
         } else {
-            parse_args(from_utf8(data.peek()).unwrap_or(""))
+            parse_args(from_utf8(peek).unwrap_or(""))
                 .filter_map(|(key, token)| {
-                    if key == CSRF_FORM_FIELD {
+                    if key == self.form_field {
                         Some(token.as_bytes())
                     } else {
                         None
                     }
                 })
                 .next()
-        }.and_then(|token| BASE64URL_NOPAD.decode(token).ok());
-        let token = token.as_mut().and_then(|token| csrf_engine.parse_token(&mut *token).ok());
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for CsrfFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "CSRF protection",
+            kind: Kind::Ignite | Kind::Request | Kind::Response,
+        }
+    }
 
-        if let Some(token) = token {
-            if let Some(cookie) = cookie {
-                if csrf_engine.verify_token_pair(&token, &cookie) {
-                    return; //if we got both token and cookie, and they match each other, we do nothing
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> fairing::Result {
+        let rocket = if let Some(status) = self.reject_status {
+            let routes = [Get, Put, Post, Delete, Options, Head, Trace, Connect, Patch]
+                .iter()
+                .map(|&method| Route::new(method, "/", RejectHandler(status)))
+                .collect::<Vec<_>>();
+            rocket.mount(CSRF_VIOLATION_PATH, routes)
+        } else {
+            rocket
+        }; //mount the handler to which violations are rerouted
+        let secret = match self.secret {
+            Some(secret) => secret,
+            None => match configured_secret(rocket.figment()) {
+                Ok(Some(material)) => derive_key(&material),
+                Ok(None) if *rocket.figment().profile() == Config::DEBUG_PROFILE => {
+                    eprintln!("[rocket_csrf] No secret key was found, you should consider set one to keep token validity across application restart");
+                    let rand = SystemRandom::new();
+                    let mut array = [0; 32];
+                    rand.fill(&mut array).unwrap();
+                    array
+                } //in debug, generate a random secret and print a warning
+                Ok(None) => {
+                    eprintln!("[rocket_csrf] No secret key was found, set `secret_key` in Rocket configuration or call `set_secret`");
+                    return Err(rocket);
                 }
+                Err(e) => {
+                    eprintln!("[rocket_csrf] {}", e);
+                    return Err(rocket);
+                }
+            },
+        }; //use provided secret if one is, else derive one from Rocket's secret key
+        let config = match rocket.figment().extract_inner::<CsrfConfig>("csrf") {
+            Ok(config) => config,
+            Err(e) if e.missing() => CsrfConfig::default(),
+            Err(e) => {
+                eprintln!("[rocket_csrf] Invalid csrf configuration: {}", e);
+                return Err(rocket);
+            }
+        };
+        let configured_keys = config
+            .previous_secret_keys
+            .iter()
+            .filter_map(|key| parse_secret(key, "previous_secret_keys").transpose())
+            .collect::<Result<Vec<_>, _>>();
+        let configured_keys = match configured_keys {
+            Ok(keys) => keys,
+            Err(e) => {
+                eprintln!("[rocket_csrf] {}", e);
+                return Err(rocket);
             }
+        };
+        let mut previous_keys = self.previous_secrets.clone();
+        previous_keys.extend(self.previous_secret_keys.iter().chain(&configured_keys).map(|key| derive_key(key)));
+        let engine = CsrfProtection::from_keys(secret, previous_keys); //previous Rocket keys go through the same derivation as the active one
+        let mounted = rocket.routes().collect::<Vec<_>>();
+        let state = match self.state(engine, config, &mounted) {
+            Ok(state) => state,
+            Err(e) => {
+                eprintln!("[rocket_csrf] Invalid csrf configuration: {}", e);
+                return Err(rocket);
+            }
+        };
+        Ok(rocket.manage(state)) //add the Csrf engine, and settings merged with configuration, to Rocket's managed state
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, data: &mut Data<'_>) {
+        let state = match request.rocket().state::<CsrfState>() {
+            Some(state) => state,
+            None => return,
+        };
+        if state.routes.is_exempt(request) {
+            return; //exempt routes are not verified at all
+        }
+
+        let verdict = self.verify(state, request, data).await;
+        let valid = verdict.is_ok();
+        request.local_cache(|| CsrfVerdict::from(verdict)); //make the verdict available to guards
+        if valid || !self.enforce_globally {
+            return; //if the request passed the configured checks, or if only guards should verify it, we do nothing
         }
 
         //Request reaching here are violating Csrf protection
 
-        for (src, dst, method) in &self.exceptions {
-            if let Some(param) = src.extract(&request.uri().to_string()) {
-                if let Some(destination) = dst.map(&param) {
-                    if let Ok(origin) = Origin::parse_owned(destination) {
-                        request.set_uri(origin);
-                        if let Some(method) = method {
-                            request.set_method(*method);
-                        }
-                        return;
+        let path = request.uri().path().to_string();
+        let uri = request.uri().to_string();
+        request.local_cache(|| ViolationUri(Some(uri.clone()))); //keep the original uri, which is rewritten below
+        let route_exception = state
+            .routes
+            .exception(request)
+            .and_then(|(src, dst, method)| Some(((dst, method), src.extract(&path)?)));
+        let request_method = request.method();
+        let exceptions = state
+            .exceptions
+            .matches(&uri)
+            .filter(|((source_method, _, _), _)| source_method.is_none_or(|m| m == request_method))
+            .map(|((_, dst, method), param)| ((dst, method), param));
+        for ((dst, method), param) in route_exception.into_iter().chain(exceptions) {
+            //exceptions of the route Rocket would use come first, route patterns don't include the query
+            if let Some(destination) = dst.map(&param) {
+                if let Ok(origin) = Origin::parse_owned(destination) {
+                    request.set_uri(origin);
+                    if let Some(method) = method {
+                        request.set_method(*method);
                     }
+                    return;
                 }
             }
         }
 
+        if self.reject_status.is_some() {
+            request.set_uri(Origin::parse(CSRF_VIOLATION_PATH).unwrap());
+            return;
+        } //if request matched no exception, and violations are rejected, reroute it to the rejecting handler, keeping its method
+
         //if request matched no exception, reroute it to default target
 
         let uri = request.uri().to_string();
-        let uri = Uri::percent_encode(&uri);
+        let uri = RawStr::new(&uri).percent_encode();
         let mut param: HashMap<&str, String> = HashMap::new();
         param.insert("uri", uri.to_string());
-        let destination = self.default_target.0.map(&param).unwrap();
+        param.insert("method", request.method().as_str().to_owned()); //Rocket already applied any `_method` override
+        let destination = state.default_target.0.map(&param).unwrap();
         let origin = Origin::parse_owned(destination).unwrap();
 
         request.set_uri(origin);
-        request.set_method(self.default_target.1)
+        request.set_method(state.default_target.1)
     }
 
-    fn on_response(&self, request: &Request, response: &mut Response) {
-        if let Some(ct) = response.content_type() {
-            if !ct.is_html() {
-                return;
-            }
-        } //if content type is not html, we do nothing
-
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let state = match request.rocket().state::<CsrfState>() {
+            Some(state) => state,
+            None => return,
+        };
+        let html = response.content_type().map(|ct| ct.is_html()).unwrap_or(true);
         let uri = request.uri().to_string();
-        if self
-            .auto_insert_disable_prefix
-            .iter()
-            .any(|prefix| uri.starts_with(prefix))
-        {
+        let auto_insert = state.auto_insert && html && !state.auto_insert_disable_prefix.is_match(&uri);
+        /* only insert tokens in html documents, and if request is not on an ignored prefix, but
+         * always send a fresh cookie if double submit is enabled
+         */
+        if !auto_insert && self.double_submit_cookie.is_none() {
             return;
-        } //if request is on an ignored prefix, ignore it
+        }
 
-        let token = match request.guard::<CsrfToken>() {
+        let token = match request.guard::<CsrfToken>().await {
             Outcome::Success(t) => {
-                response.adjoin_header(request.cookies().get(CSRF_COOKIE_NAME).unwrap());
+                let prefix = format!("{}=", self.cookie_name);
+                let sent = response.headers().get("Set-Cookie").any(|cookie| cookie.starts_with(&prefix));
+                match request.cookies().get_pending(&self.cookie_name) {
+                    Some(cookie) if !sent => response.adjoin_header(cookie),
+                    _ => (),
+                } //there is no csrf cookie with stateless tokens, and guards of the handler may have set it already
+                if let Some(double_submit_cookie) = &self.double_submit_cookie {
+                    response.adjoin_header(
+                        Cookie::build((
+                            double_submit_cookie.clone(),
+                            from_utf8(t.value()).unwrap().to_owned(),
+                        ))
+                        .http_only(false)
+                        .secure(state.secure_cookies)
+                        .same_site(SameSite::Strict)
+                        .path("/")
+                        .max_age(Duration::seconds(state.duration as i64))
+                        .build(),
+                    );
+                }
                 t
             } //guard can't add/remove cookies in on_response, add headers manually
             Outcome::Forward(_) => {
-                if request.cookies().get(CSRF_COOKIE_NAME).is_some() {
-                    response.adjoin_header(
-                        Cookie::build(CSRF_COOKIE_NAME, "")
-                            .max_age(Duration::zero())
-                            .finish(),
-                    );
+                let double_submit_cookie = self.double_submit_cookie.as_ref();
+                for name in Some(&self.cookie_name).into_iter().chain(double_submit_cookie) {
+                    if request.cookies().get(name).is_some() {
+                        response.adjoin_header(
+                            Cookie::build((name.clone(), ""))
+                                .path("/")
+                                .max_age(Duration::ZERO)
+                                .build(),
+                        );
+                    }
                 }
                 return;
             } //guard can't add/remove cookies in on_response, add headers manually
-            Outcome::Failure(_) => return,
+            Outcome::Error(_) => return,
         }; /* if we can't get a token, leave request unchanged, this probably
             * means the request had no cookies from the begining
             */
 
-        let body = response.take_body(); //take request body from Rocket
-        if body.is_none() {
+        if !auto_insert {
+            return;
+        }
+
+        if response.body().is_none() {
             return;
         } //if there was no body, leave it that way
-        let body = body.unwrap();
-
-        if let Sized(body_reader, len) = body {
-            if len <= self.auto_insert_max_size {
-                //if this is a small enought body, process the full body
-                let mut res = Vec::with_capacity(len as usize);
-                CsrfProxy::from(body_reader, token.value())
-                    .read_to_end(&mut res)
-                    .unwrap();
-                response.set_sized_body(Cursor::new(res));
+
+        let engine = &state.engine;
+        let session = state.session_id(request).unwrap_or_default();
+        let unscoped = BASE64URL_NOPAD.decode(token.value()).unwrap_or_default();
+        let base = request.uri().path().to_string();
+        let (token_mode, duration, masked_tokens) = (state.token_mode, state.duration, state.masked_tokens);
+        let scoped_token = move |method: &str, action: &str| {
+            let method = Method::from_str(method).unwrap_or(Post); //Rocket ignore invalid methods
+            let scope = token_scope(method, &resolve_action(&base, action));
+            let token = match token_mode {
+                TokenMode::Cookie => engine.scope_token(&unscoped, duration, &session, &scope, masked_tokens),
+                TokenMode::Stateless => engine.generate_signed_token(duration, &session, &scope),
+                TokenMode::Stored => engine.scope_stored_id(&unscoped, &session, &scope),
+            }
+            .ok()?;
+            Some(BASE64URL_NOPAD.encode(&token).into_bytes())
+        };
+        let proxy = |body| {
+            let proxy = CsrfProxy::from(body, token.value(), &self.form_field);
+            if self.scoped_tokens {
+                proxy.with_scoped_tokens(scoped_token)
             } else {
-                //if body is of known but long size, change it to a stream to preserve memory, by encapsulating it into our "proxy" struct
-                let body = body_reader;
-                response.set_streamed_body(Box::new(CsrfProxy::from(body, token.value())));
+                proxy
+            }
+        };
+
+        let body = response.body_mut().take(); //take request body from Rocket
+        if let Some(len) = body.preset_size().filter(|&len| len as u64 <= state.auto_insert_max_size) {
+            //if this is a small enought body, process the full body
+            let mut res = Vec::with_capacity(len);
+            match proxy(Box::pin(body)).read_to_end(&mut res).await {
+                Ok(_) => response.set_sized_body(res.len(), Cursor::new(res)),
+                Err(e) => {
+                    eprintln!("[rocket_csrf] Failed to read response body: {}", e);
+                    response.set_status(Status::InternalServerError);
+                    response.set_sized_body(0, Cursor::new(Vec::new()));
+                } //the body was already taken, don't send a truncated page as a success
             }
         } else {
-            //if body is of unknown size, encapsulate it into our "proxy" struct
-            let body = body.into_inner();
-            response.set_streamed_body(Box::new(CsrfProxy::from(body, token.value())));
+            //if body is of unknown or long size, encapsulate it into our "proxy" struct and stream it
+            response.set_streamed_body(proxy(Box::pin(body)));
         }
     }
 }
@@ -454,11 +1700,13 @@ impl Fairing for CsrfFairing {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use {CSRF_COOKIE_NAME, CSRF_FORM_FIELD};
+    use crate::{CsrfViolation, PathErrorKind, VerifiedCsrf, CSRF_COOKIE_NAME, CSRF_FORM_FIELD};
     use rocket::{
-        http::{Cookie, Header, Method},
-        local::{Client, LocalRequest},
-        Rocket,
+        error::ErrorKind,
+        figment::providers::{Format, Toml},
+        http::{ContentType, Cookie, Header, Method, Status},
+        local::blocking::{Client, LocalRequest},
+        Build, Rocket,
     };
 
     fn default_builder() -> CsrfFairingBuilder {
@@ -476,46 +1724,64 @@ mod tests {
             )])
     }
 
-    fn default_rocket(csrf_fairing: CsrfFairing) -> Rocket {
-        ::rocket::ignite()
+    fn default_rocket(csrf_fairing: CsrfFairing) -> Rocket<Build> {
+        custom_rocket(rocket::Config::figment(), csrf_fairing)
+    }
+
+    fn custom_rocket(figment: Figment, csrf_fairing: CsrfFairing) -> Rocket<Build> {
+        ::rocket::custom(figment)
             .mount(
                 "/",
                 routes![
                     index,
                     post_index,
+                    delete_index,
+                    guarded,
+                    guarded_reason,
                     token,
+                    token_page,
                     csrf,
+                    csrf_reason,
+                    csrf_method,
                     get_ex1,
                     post_ex1,
                     target_ex1,
                     post_ex2,
                     target_ex2,
-                    static_route
+                    route_ex_static,
+                    route_ex_dynamic,
+                    route_ex_target,
+                    webhook,
+                    forms,
+                    post_forms,
+                    static_route,
+                    stream,
+                    failing_body
                 ],
             )
             .attach(csrf_fairing)
     }
 
     fn get_token(client: &Client) -> (String, String) {
-        let mut response = client
+        let response = client
             .get("/token")
             .cookie(Cookie::new("some", "cookie"))
             .dispatch(); //get token and cookie
-        let token = response.body_string().unwrap();
         let cookie = response
             .headers()
             .get("set-cookie")
             .next()
             .unwrap()
-            .split(|c| c == '=' || c == ';')
+            .split(['=', ';'])
             .nth(1)
             .unwrap()
             .to_owned();
+        let token = response.into_string().unwrap();
         (token, cookie)
     }
 
-    fn post_token(client: &Client, path: String, token: String, cookie: String) -> LocalRequest {
-        let token = if token.len() > 0 {
+    fn post_token(client: &Client, path: String, token: String, cookie: String) -> LocalRequest<'_> {
+        let token = if !token.is_empty() {
             let mut t = Vec::new();
             t.append(&mut CSRF_FORM_FIELD.as_bytes().to_vec());
             t.push(0x3D); //'='
@@ -535,93 +1801,198 @@ mod tests {
     #[test]
     fn test_redirection_on_failure() {
         let rocket = default_rocket(default_builder().finalize().unwrap());
-        let client = Client::new(rocket).expect("valid rocket instance");
+        let client = Client::tracked(rocket).expect("valid rocket instance");
 
-        let mut response = client.post("/").cookie(Cookie::new("some", "cookie")).dispatch(); //violation well detected
-        assert_eq!(response.body_string(), Some("violation".to_owned()));
+        let response = client.post("/").cookie(Cookie::new("some", "cookie")).dispatch(); //violation well detected
+        assert_eq!(response.into_string(), Some("violation".to_owned()));
 
-        let mut response = client.post("/ex1").cookie(Cookie::new("some", "cookie")).dispatch(); //redirection on post
-        assert_eq!(response.body_string(), Some("target-ex1".to_owned()));
+        let response = client.post("/ex1").cookie(Cookie::new("some", "cookie")).dispatch(); //redirection on post
+        assert_eq!(response.into_string(), Some("target-ex1".to_owned()));
 
-        let mut response = client.post("/ex2/abcd").cookie(Cookie::new("some", "cookie")).dispatch(); //redirection with dyn part
-        assert_eq!(response.body_string(), Some("abcd".to_owned()));
+        let response = client.post("/ex2/abcd").cookie(Cookie::new("some", "cookie")).dispatch(); //redirection with dyn part
+        assert_eq!(response.into_string(), Some("abcd".to_owned()));
     }
 
     #[test]
     fn test_non_redirection() {
         let rocket = default_rocket(default_builder().finalize().unwrap());
-        let client = Client::new(rocket).expect("valid rocket instance");
+        let client = Client::tracked(rocket).expect("valid rocket instance");
 
-        let mut response = client.get("/ex1").cookie(Cookie::new("some", "cookie")).dispatch(); //no redirection on get
-        assert_eq!(response.body_string(), Some("get-ex1".to_owned()));
+        let response = client.get("/ex1").cookie(Cookie::new("some", "cookie")).dispatch(); //no redirection on get
+        assert_eq!(response.into_string(), Some("get-ex1".to_owned()));
 
         let (token, cookie) = get_token(&client);
 
-        let mut response =
+        let response =
             post_token(&client, "/".to_owned(), token.clone(), cookie.clone()).cookie(Cookie::new("some", "cookie")).dispatch();
-        assert_eq!(response.body_string(), Some("success".to_owned()));
+        assert_eq!(response.into_string(), Some("success".to_owned()));
 
-        let mut response =
+        let response =
             post_token(&client, "/ex1".to_owned(), token.clone(), cookie.clone()).cookie(Cookie::new("some", "cookie")).dispatch();
-        assert_eq!(response.body_string(), Some("post-ex1".to_owned()));
+        assert_eq!(response.into_string(), Some("post-ex1".to_owned()));
 
-        let mut response = post_token(
+        let response = post_token(
             &client,
             "/ex2/some-url".to_owned(),
             token.clone(),
             cookie.clone(),
         ).cookie(Cookie::new("some", "cookie")).dispatch();
-        assert_eq!(response.body_string(), Some("valid-dyn-req".to_owned()));
+        assert_eq!(response.into_string(), Some("valid-dyn-req".to_owned()));
     }
 
     #[test]
     fn test_token_timeout() {
         let rocket = default_rocket(default_builder().set_timeout(5).finalize().unwrap());
-        let client = Client::new(rocket).expect("valid rocket instance");
+        let client = Client::tracked(rocket).expect("valid rocket instance");
 
         let (token, cookie) = get_token(&client);
 
-        let mut response =
+        let response =
             post_token(&client, "/".to_owned(), token.clone(), cookie.clone()).cookie(Cookie::new("some", "cookie")).dispatch();
-        assert_eq!(response.body_string(), Some("success".to_owned()));
+        assert_eq!(response.into_string(), Some("success".to_owned()));
         ::std::thread::sleep(::std::time::Duration::from_secs(6));
 
         //access / with timed out token
-        let mut response =
+        let response =
             post_token(&client, "/".to_owned(), token.clone(), cookie.clone()).cookie(Cookie::new("some", "cookie")).dispatch();
-        assert_eq!(response.body_string(), Some("violation".to_owned()));
+        assert_eq!(response.into_string(), Some("violation".to_owned()));
+    }
+
+    #[test]
+    fn test_first_visit_token() {
+        let rocket = default_rocket(default_builder().finalize().unwrap());
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        //the page renders a token before any csrf cookie exists, auto-insert must not replace it
+        let response = client.get("/token-page").cookie(Cookie::new("some", "cookie")).dispatch();
+        let cookies = response
+            .headers()
+            .get("set-cookie")
+            .filter(|cookie| cookie.starts_with(&format!("{}=", CSRF_COOKIE_NAME)))
+            .map(|cookie| cookie.split(['=', ';']).nth(1).unwrap().to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(cookies.len(), 1);
+        let body = response.into_string().unwrap();
+        let rendered = body.split("<p>").nth(1).unwrap().split("</p>").next().unwrap();
+        let inserted = body.split("value=\"").nth(1).unwrap().split('"').next().unwrap();
+        for token in [rendered, inserted] {
+            let response = post_token(&client, "/".to_owned(), token.to_owned(), cookies[0].clone())
+                .cookie(Cookie::new("some", "cookie"))
+                .dispatch();
+            assert_eq!(response.into_string(), Some("success".to_owned()));
+        }
     }
 
     #[test]
     fn test_invalid_token_pair() {
         let rocket1 = default_rocket(default_builder().set_secret([0; 32]).finalize().unwrap());
-        let client1 = Client::new(rocket1).expect("valid rocket instance");
+        let client1 = Client::tracked(rocket1).expect("valid rocket instance");
         let rocket2 = default_rocket(default_builder().set_secret([0; 32]).finalize().unwrap());
-        let client2 = Client::new(rocket2).expect("valid rocket instance");
+        let client2 = Client::tracked(rocket2).expect("valid rocket instance");
 
         let (token, cookie) = get_token(&client1);
 
         //having only one part fail
-        let mut response =
+        let response =
             post_token(&client2, "/".to_owned(), token.clone(), "".to_owned()).cookie(Cookie::new("some", "cookie")).dispatch();
-        assert_eq!(response.body_string(), Some("violation".to_owned()));
+        assert_eq!(response.into_string(), Some("violation".to_owned()));
 
-        let mut response =
+        let response =
             post_token(&client1, "/".to_owned(), "".to_owned(), cookie.clone()).cookie(Cookie::new("some", "cookie")).dispatch();
-        assert_eq!(response.body_string(), Some("violation".to_owned()));
+        assert_eq!(response.into_string(), Some("violation".to_owned()));
 
         let (token2, _cookie2) = get_token(&client2);
 
         //having 2 incompatible parts fail
-        let mut response =
+        let response =
             post_token(&client1, "/".to_owned(), token2.clone(), cookie.clone()).cookie(Cookie::new("some", "cookie")).dispatch();
-        assert_eq!(response.body_string(), Some("violation".to_owned()));
+        assert_eq!(response.into_string(), Some("violation".to_owned()));
+    }
+
+    #[test]
+    fn test_secret_rotation() {
+        let old_rocket = default_rocket(default_builder().set_secret([0; 32]).finalize().unwrap());
+        let old_client = Client::untracked(old_rocket).expect("valid rocket instance");
+        let (token, cookie) = get_token(&old_client);
+
+        //tokens from the previous key are still accepted
+        let rotated_rocket = default_rocket(
+            default_builder()
+                .set_secret([1; 32])
+                .set_previous_secrets(vec![[0; 32]])
+                .finalize()
+                .unwrap(),
+        );
+        let rotated_client = Client::untracked(rotated_rocket).expect("valid rocket instance");
+        let response = post_token(&rotated_client, "/".to_owned(), token.clone(), cookie.clone())
+            .cookie(Cookie::new("some", "cookie"))
+            .dispatch();
+        assert_eq!(response.into_string(), Some("success".to_owned()));
+
+        //new tokens are sealed with the active key only
+        let (new_token, new_cookie) = get_token(&rotated_client);
+        let response = post_token(&old_client, "/".to_owned(), new_token, new_cookie)
+            .cookie(Cookie::new("some", "cookie"))
+            .dispatch();
+        assert_eq!(response.into_string(), Some("violation".to_owned()));
+
+        //once dropped from the ring, the old key is refused
+        let new_rocket = default_rocket(default_builder().set_secret([1; 32]).finalize().unwrap());
+        let new_client = Client::untracked(new_rocket).expect("valid rocket instance");
+        let response = post_token(&new_client, "/".to_owned(), token, cookie)
+            .cookie(Cookie::new("some", "cookie"))
+            .dispatch();
+        assert_eq!(response.into_string(), Some("violation".to_owned()));
+    }
+
+    #[test]
+    fn test_session_binding() {
+        let rocket = default_rocket(
+            default_builder()
+                .set_session_binder(|request: &Request<'_>| {
+                    request.cookies().get("user").map(|cookie| cookie.value().as_bytes().to_vec())
+                })
+                .finalize()
+                .unwrap(),
+        );
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let response = client.get("/token").cookie(Cookie::new("user", "alice")).dispatch();
+        let cookie = response.cookies().get(CSRF_COOKIE_NAME).unwrap().value().to_owned();
+        let token = response.into_string().unwrap();
+
+        let post = |user: Option<&str>| {
+            let request = post_token(&client, "/".to_owned(), token.clone(), cookie.clone())
+                .cookie(Cookie::new("some", "cookie"));
+            match user {
+                Some(user) => request.cookie(Cookie::new("user", user.to_owned())),
+                None => request,
+            }
+            .dispatch()
+            .into_string()
+        };
+        assert_eq!(post(Some("alice")), Some("success".to_owned()));
+        assert_eq!(post(Some("bob")), Some("violation".to_owned())); //another user logged in
+        assert_eq!(post(None), Some("violation".to_owned())); //the user logged out
+
+        //a cookie of another session is replaced by a new one, bound to the current session
+        let response = client
+            .get("/token")
+            .cookie(Cookie::new("user", "bob"))
+            .cookie(Cookie::new(CSRF_COOKIE_NAME, cookie.clone()))
+            .dispatch();
+        let cookie = response.cookies().get(CSRF_COOKIE_NAME).unwrap().value().to_owned();
+        let token = response.into_string().unwrap();
+        let response = post_token(&client, "/".to_owned(), token, cookie)
+            .cookie(Cookie::new("user", "bob"))
+            .dispatch();
+        assert_eq!(response.into_string(), Some("success".to_owned()));
     }
 
     #[test]
     fn test_multiple_parametters() {
         let rocket = default_rocket(default_builder().finalize().unwrap());
-        let client = Client::new(rocket).expect("valid rocket instance");
+        let client = Client::tracked(rocket).expect("valid rocket instance");
 
         let (token, cookie) = get_token(&client);
 
@@ -631,7 +2002,7 @@ mod tests {
         body.push(0x3D); //'='
         body.append(&mut token.as_bytes().to_vec());
         body.append(&mut "&key2=value2".as_bytes().to_vec());
-        let mut response = client
+        let response = client
             .post("/")
             .body(body)
             .cookie(Cookie::new("something", "before"))
@@ -639,7 +2010,7 @@ mod tests {
             .cookie(Cookie::new("and", "after"))
             .dispatch();
 
-        assert_eq!(response.body_string(), Some("success".to_owned()));
+        assert_eq!(response.into_string(), Some("success".to_owned()));
     }
 
     #[test]
@@ -662,7 +2033,7 @@ How are you?
 
 -----------------------------9051914041544843365972754266--";
         let rocket = default_rocket(default_builder().finalize().unwrap());
-        let client = Client::new(rocket).expect("valid rocket instance");
+        let client = Client::tracked(rocket).expect("valid rocket instance");
 
         let (token, cookie) = get_token(&client);
 
@@ -672,7 +2043,7 @@ How are you?
         body.append(&mut body_middle.as_bytes().to_vec());
         body.append(&mut token.as_bytes().to_vec());
         body.append(&mut body_after.as_bytes().to_vec());
-        let mut response = client
+        let response = client
             .post("/")
             .header(Header::new(
                 "Content-Type",
@@ -685,14 +2056,14 @@ How are you?
             .cookie(Cookie::new("some", "cookie"))
             .dispatch();
 
-        assert_eq!(response.body_string(), Some("success".to_owned()));
+        assert_eq!(response.into_string(), Some("success".to_owned()));
         let mut body = Vec::new();
         body.append(&mut body_before.as_bytes().to_vec());
         body.append(&mut CSRF_FORM_FIELD.as_bytes().to_vec());
         body.append(&mut body_middle.as_bytes().to_vec());
         body.append(&mut "not_a_token".as_bytes().to_vec());
         body.append(&mut body_after.as_bytes().to_vec());
-        let mut response = client
+        let response = client
             .post("/")
             .header(Header::new(
                 "Content-Type",
@@ -705,96 +2076,1065 @@ How are you?
             .cookie(Cookie::new("some", "cookie"))
             .dispatch();
 
-        assert_eq!(response.body_string(), Some("violation".to_owned()));
+        assert_eq!(response.into_string(), Some("violation".to_owned()));
     }
 
     #[test]
-    fn test_token_insertion() {
+    fn test_custom_names() {
         let rocket = default_rocket(
             default_builder()
-                .set_auto_insert_disable_prefix(vec!["/static".to_owned()])
+                .set_cookie_name("session-csrf".to_owned())
+                .set_form_field_name("authenticity_token".to_owned())
                 .finalize()
                 .unwrap(),
         );
-        let client = Client::new(rocket).expect("valid rocket instance");
+        let client = Client::tracked(rocket).expect("valid rocket instance");
 
-        let mut response = client
-            .get("/")
+        let response = client
+            .get("/token")
             .cookie(Cookie::new("some", "cookie"))
-            .dispatch(); //token well inserted
+            .dispatch();
         assert!(
-            response.body_string().unwrap().len()
-                > "<div><form method='POST'></form></div>".len()
-                    + "<input type=\"hidden\" name=\"csrf-token\" value=\"\"/>".len()
+            response
+                .headers()
+                .get_one("set-cookie")
+                .unwrap()
+                .starts_with("session-csrf=")
         );
+        let token = response.into_string().unwrap();
 
-        let mut response = client
-            .get("/static/something")
+        let response = client
+            .post("/")
+            .body(format!("authenticity_token={}", token))
+            .dispatch();
+        assert_eq!(response.into_string(), Some("success".to_owned()));
+
+        let response = client
+            .post("/")
+            .body(format!("{}={}", CSRF_FORM_FIELD, token))
+            .dispatch();
+        assert_eq!(response.into_string(), Some("violation".to_owned()));
+
+        let response = client
+            .get("/")
             .cookie(Cookie::new("some", "cookie"))
-            .dispatch(); //url well ignored by token inserter
-        assert_eq!(
-            response.body_string(),
-            Some("<div><form method='POST'></form></div>".to_owned())
-        );
+            .dispatch();
+        assert!(response
+            .into_string()
+            .unwrap()
+            .contains("name=\"authenticity_token\""));
     }
 
     #[test]
-    fn test_auto_insert_disabled() {
-        let rocket = default_rocket(default_builder().set_auto_insert(false).finalize().unwrap());
-        let client = Client::new(rocket).expect("valid rocket instance");
+    fn test_token_in_header() {
+        let rocket = default_rocket(default_builder().finalize().unwrap());
+        let client = Client::tracked(rocket).expect("valid rocket instance");
 
-        let mut response = client
-            .get("/")
-            .cookie(Cookie::new("some", "cookie"))
+        let (token, cookie) = get_token(&client);
+
+        let response = client
+            .post("/")
+            .header(ContentType::JSON)
+            .header(Header::new("X-CSRF-Token", token.clone()))
+            .body("{}")
+            .cookie(Cookie::new(CSRF_COOKIE_NAME, cookie.clone()))
             .dispatch();
-        assert_eq!(
-            response.body_string(),
-            Some("<div><form method='POST'></form></div>".to_owned())
-        );
+        assert_eq!(response.into_string(), Some("success".to_owned()));
+
+        let response = client
+            .delete("/")
+            .header(Header::new("X-XSRF-Token", token.clone()))
+            .cookie(Cookie::new(CSRF_COOKIE_NAME, cookie.clone()))
+            .dispatch();
+        assert_eq!(response.into_string(), Some("deleted".to_owned()));
+
+        let response = client
+            .delete("/")
+            .header(Header::new("X-Other-Token", token.clone()))
+            .cookie(Cookie::new(CSRF_COOKIE_NAME, cookie.clone()))
+            .dispatch();
+        assert_eq!(response.into_string(), Some("violation".to_owned()));
+
+        let rocket = default_rocket(default_builder().set_token_headers(Vec::new()).finalize().unwrap());
+        let client = Client::tracked(rocket).expect("valid rocket instance");
+
+        let (token, cookie) = get_token(&client);
+
+        let response = client
+            .delete("/")
+            .header(Header::new("X-CSRF-Token", token))
+            .cookie(Cookie::new(CSRF_COOKIE_NAME, cookie))
+            .dispatch();
+        assert_eq!(response.into_string(), Some("violation".to_owned()));
     }
 
     #[test]
-    fn test_auto_insert_stream() {
+    fn test_double_submit() {
+        let rocket = default_rocket(default_builder().set_double_submit(true).finalize().unwrap());
+        let client = Client::tracked(rocket).expect("valid rocket instance");
+
+        let response = client
+            .get("/ex1")
+            .cookie(Cookie::new("some", "cookie"))
+            .dispatch(); //not an html page, but still get a token
+        let xsrf = response
+            .headers()
+            .get("set-cookie")
+            .find(|c| c.starts_with("XSRF-TOKEN="))
+            .unwrap()
+            .to_owned();
+        assert!(!xsrf.contains("HttpOnly"));
+        assert!(xsrf.contains("Secure"));
+        let token = xsrf.split(['=', ';']).nth(1).unwrap().to_owned();
+
+        let response = client
+            .post("/")
+            .header(ContentType::JSON)
+            .header(Header::new("X-XSRF-TOKEN", token))
+            .body("{}")
+            .dispatch();
+        assert_eq!(response.into_string(), Some("success".to_owned()));
+
+        let response = client.post("/").header(ContentType::JSON).body("{}").dispatch();
+        assert_eq!(response.into_string(), Some("violation".to_owned()));
+
         let rocket = default_rocket(
             default_builder()
-                .set_auto_insert_max_chunk_size(1)
+                .set_token_headers(Vec::new())
+                .set_double_submit_names("ANGULAR-XSRF".to_owned(), "X-Angular-XSRF".to_owned())
                 .finalize()
                 .unwrap(),
         );
-        let client = Client::new(rocket).expect("valid rocket instance");
+        let client = Client::tracked(rocket).expect("valid rocket instance");
 
-        let mut response = client
-            .get("/")
+        let response = client
+            .get("/ex1")
             .cookie(Cookie::new("some", "cookie"))
-            .dispatch(); //token well inserted
-        assert!(
-            response.body_string().unwrap().len()
-                > "<div><form method='POST'></form></div>".len()
-                    + "<input type=\"hidden\" name=\"csrf-token\" value=\"\"/>".len()
-        );
+            .dispatch();
+        let token = response
+            .cookies()
+            .get("ANGULAR-XSRF")
+            .unwrap()
+            .value()
+            .to_owned();
 
-        //TODO test stream body
+        let response = client
+            .post("/")
+            .header(Header::new("X-Angular-XSRF", token.clone()))
+            .dispatch();
+        assert_eq!(response.into_string(), Some("success".to_owned()));
+
+        //without other cookies, ours are removed from the path they were set on
+        let rocket = default_rocket(default_builder().set_double_submit(true).finalize().unwrap());
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        let response = client.get("/ex1").cookie(Cookie::new("XSRF-TOKEN", token)).dispatch();
+        let removed = response.headers().get("set-cookie").collect::<Vec<_>>();
+        assert_eq!(removed.len(), 1);
+        assert!(removed[0].starts_with("XSRF-TOKEN=;") && removed[0].contains("Path=/") && removed[0].contains("Max-Age=0"));
+
+        //cookies may be sent over plain http
+        let toml = "[default.csrf]\nsecure_cookies = false";
+        let figment = rocket::Config::figment().merge(Toml::string(toml).nested());
+        for rocket in [
+            default_rocket(default_builder().set_double_submit(true).set_secure_cookies(false).finalize().unwrap()),
+            custom_rocket(figment, default_builder().set_double_submit(true).finalize().unwrap()),
+        ] {
+            let client = Client::untracked(rocket).expect("valid rocket instance");
+            let response = client.get("/ex1").cookie(Cookie::new("some", "cookie")).dispatch();
+            let cookies = response.headers().get("set-cookie").collect::<Vec<_>>();
+            assert_eq!(cookies.len(), 2);
+            assert!(cookies.iter().all(|cookie| !cookie.contains("Secure")));
+        }
     }
 
     #[test]
-    fn test_key_from_env() {
-        env::set_var(
-            "ROCKET_SECRET_KEY",
-            "BAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=",
+    fn test_origin_check() {
+        let rocket = default_rocket(
+            default_builder()
+                .set_check_mode(CheckMode::OriginOnly)
+                .set_allowed_origins(vec!["https://example.com".to_owned(), "example.org".to_owned()])
+                .finalize()
+                .unwrap(),
         );
+        let client = Client::tracked(rocket).expect("valid rocket instance");
+
+        let post_from = |header: &'static str, value: &'static str| {
+            client
+                .post("/")
+                .header(Header::new(header, value))
+                .cookie(Cookie::new("some", "cookie"))
+                .dispatch()
+                .into_string()
+                .unwrap()
+        };
+        assert_eq!(post_from("Origin", "https://example.com"), "success");
+        assert_eq!(post_from("Origin", "http://example.com"), "violation");
+        assert_eq!(post_from("Origin", "http://example.org"), "success");
+        assert_eq!(post_from("Origin", "https://evil.com"), "violation");
+        assert_eq!(post_from("Referer", "https://example.com/form"), "success");
+        assert_eq!(post_from("Referer", "https://evil.com/form"), "violation");
+        assert_eq!(post_from("X-Nothing", "https://example.com"), "violation");
+        let response = client
+            .post("/")
+            .header(Header::new("Origin", "null"))
+            .header(Header::new("Referer", "https://example.com/form"))
+            .cookie(Cookie::new("some", "cookie"))
+            .dispatch(); //an opaque origin doesn't fallback to the Referer
+        assert_eq!(response.into_string(), Some("violation".to_owned()));
+
+        let rocket = default_rocket(
+            default_builder()
+                .set_check_mode(CheckMode::OriginOnly)
+                .finalize()
+                .unwrap(),
+        );
+        let client = Client::tracked(rocket).expect("valid rocket instance");
+
+        let response = client
+            .post("/")
+            .header(Header::new("Host", "example.com"))
+            .header(Header::new("Origin", "https://example.com"))
+            .cookie(Cookie::new("some", "cookie"))
+            .dispatch(); //same origin accepted without explicit list
+        assert_eq!(response.into_string(), Some("success".to_owned()));
+    }
+
+    #[test]
+    fn test_token_and_origin_check() {
+        let builder = || default_builder().set_allowed_origins(vec!["example.com".to_owned()]);
+        let rocket = default_rocket(builder().set_check_mode(CheckMode::TokenAndOrigin).finalize().unwrap());
+        let client = Client::tracked(rocket).expect("valid rocket instance");
+
+        let (token, cookie) = get_token(&client);
+
+        let response = post_token(&client, "/".to_owned(), token.clone(), cookie.clone())
+            .header(Header::new("Origin", "https://example.com"))
+            .dispatch();
+        assert_eq!(response.into_string(), Some("success".to_owned()));
+
+        let response = post_token(&client, "/".to_owned(), token.clone(), cookie.clone())
+            .header(Header::new("Origin", "https://evil.com"))
+            .dispatch();
+        assert_eq!(response.into_string(), Some("violation".to_owned()));
+
+        let response = post_token(&client, "/".to_owned(), "".to_owned(), cookie.clone())
+            .header(Header::new("Origin", "https://example.com"))
+            .dispatch();
+        assert_eq!(response.into_string(), Some("violation".to_owned()));
+
+        let rocket = default_rocket(builder().set_check_mode(CheckMode::Either).finalize().unwrap());
+        let client = Client::tracked(rocket).expect("valid rocket instance");
+
+        let (token, cookie) = get_token(&client);
+
+        let response = post_token(&client, "/".to_owned(), token, cookie.clone()).dispatch();
+        assert_eq!(response.into_string(), Some("success".to_owned()));
+
+        let response = post_token(&client, "/".to_owned(), "".to_owned(), cookie.clone())
+            .header(Header::new("Origin", "https://example.com"))
+            .dispatch();
+        assert_eq!(response.into_string(), Some("success".to_owned()));
+
+        let response = post_token(&client, "/".to_owned(), "".to_owned(), cookie)
+            .header(Header::new("Origin", "https://evil.com"))
+            .dispatch();
+        assert_eq!(response.into_string(), Some("violation".to_owned()));
+    }
+
+    #[test]
+    fn test_fetch_metadata() {
+        let rocket = default_rocket(
+            default_builder()
+                .set_fetch_metadata(true)
+                .set_fetch_metadata_allowed_paths(vec!["/ex2".to_owned()])
+                .finalize()
+                .unwrap(),
+        );
+        let client = Client::tracked(rocket).expect("valid rocket instance");
+
+        let post_from = |path: &'static str, site: &'static str| {
+            client
+                .post(path)
+                .header(Header::new("Sec-Fetch-Site", site))
+                .header(Header::new("Sec-Fetch-Mode", "navigate"))
+                .header(Header::new("Sec-Fetch-Dest", "document"))
+                .cookie(Cookie::new("some", "cookie"))
+                .dispatch()
+                .into_string()
+                .unwrap()
+        };
+        assert_eq!(post_from("/", "same-origin"), "success");
+        assert_eq!(post_from("/", "none"), "success");
+        assert_eq!(post_from("/", "same-site"), "violation"); //no token
+        assert_eq!(post_from("/", "cross-site"), "violation");
+        assert_eq!(post_from("/ex2/abcd", "cross-site"), "abcd"); //allowed path, but no token
+
+        let (token, cookie) = get_token(&client);
+        let response = post_token(&client, "/ex2/abcd".to_owned(), token.clone(), cookie.clone())
+            .header(Header::new("Sec-Fetch-Site", "cross-site"))
+            .dispatch();
+        assert_eq!(response.into_string(), Some("valid-dyn-req".to_owned()));
+
+        let response = post_token(&client, "/".to_owned(), token.clone(), cookie.clone())
+            .header(Header::new("Sec-Fetch-Site", "cross-site"))
+            .dispatch(); //even with a valid token
+        assert_eq!(response.into_string(), Some("violation".to_owned()));
+
+        let response = post_token(&client, "/".to_owned(), token.clone(), cookie.clone())
+            .header(Header::new("Sec-Fetch-Site", "same-site"))
+            .dispatch(); //same-site falls back to the token
+        assert_eq!(response.into_string(), Some("success".to_owned()));
+
+        let response = post_token(&client, "/ex2abcd".to_owned(), token.clone(), cookie.clone())
+            .header(Header::new("Sec-Fetch-Site", "cross-site"))
+            .dispatch(); //allowed paths match whole segments
+        assert_eq!(response.into_string(), Some("violation".to_owned()));
+
+        let response = post_token(&client, "/".to_owned(), token, cookie)
+            .dispatch(); //fallback to token without headers
+        assert_eq!(response.into_string(), Some("success".to_owned()));
+
+        let rocket = default_rocket(
+            default_builder()
+                .set_fetch_metadata(true)
+                .set_fetch_metadata_reject_same_site(true)
+                .finalize()
+                .unwrap(),
+        );
+        let client = Client::tracked(rocket).expect("valid rocket instance");
+
+        let (token, cookie) = get_token(&client);
+        let response = post_token(&client, "/".to_owned(), token, cookie)
+            .header(Header::new("Sec-Fetch-Site", "same-site"))
+            .dispatch();
+        assert_eq!(response.into_string(), Some("violation".to_owned()));
+
+        let rocket = default_rocket(
+            default_builder()
+                .set_fetch_metadata(true)
+                .set_fetch_metadata_allow_navigation(true)
+                .finalize()
+                .unwrap(),
+        );
+        let client = Client::tracked(rocket).expect("valid rocket instance");
+
+        let (token, cookie) = get_token(&client);
+        let navigate = |token: String| {
+            post_token(&client, "/".to_owned(), token, cookie.clone())
+                .header(Header::new("Sec-Fetch-Site", "cross-site"))
+                .header(Header::new("Sec-Fetch-Mode", "navigate"))
+                .dispatch()
+                .into_string()
+                .unwrap()
+        };
+        assert_eq!(navigate(token), "success");
+        assert_eq!(navigate("".to_owned()), "violation");
+
+        let response = client
+            .post("/")
+            .header(Header::new("Sec-Fetch-Site", "cross-site"))
+            .header(Header::new("Sec-Fetch-Mode", "cors"))
+            .cookie(Cookie::new("some", "cookie"))
+            .dispatch();
+        assert_eq!(response.into_string(), Some("violation".to_owned()));
+    }
+
+    #[test]
+    fn test_verified_csrf_guard() {
+        let rocket = default_rocket(default_builder().set_enforce_globally(false).finalize().unwrap());
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let response = client.post("/guarded").dispatch(); //no session
+        assert_eq!(response.into_string(), Some("guarded".to_owned()));
+
+        let response = client.post("/").cookie(Cookie::new("some", "cookie")).dispatch(); //not globally enforced
+        assert_eq!(response.into_string(), Some("success".to_owned()));
+
+        let response = client.post("/guarded").cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+
+        let response = client.post("/guarded-reason").cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.into_string(), Some("missing csrf cookie".to_owned()));
+
+        let (token, cookie) = get_token(&client);
+        let response = post_token(&client, "/guarded".to_owned(), token, cookie).dispatch();
+        assert_eq!(response.into_string(), Some("guarded".to_owned()));
+
+        let rocket = default_rocket(
+            default_builder()
+                .add_exceptions(vec![("/guarded".to_owned(), "/guarded".to_owned(), None)])
+                .finalize()
+                .unwrap(),
+        );
+        let client = Client::tracked(rocket).expect("valid rocket instance");
+
+        let response = client.post("/guarded").cookie(Cookie::new("some", "cookie")).dispatch(); //exception don't bypass the guard
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+
+    #[test]
+    fn test_reject_status() {
+        let rocket = default_rocket(default_builder().set_reject_status(Status::Forbidden).finalize().unwrap())
+            .register("/", catchers![forbidden]);
+        let client = Client::tracked(rocket).expect("valid rocket instance");
+
+        let response = client.post("/?some=query").cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        assert_eq!(response.into_string(), Some("missing csrf cookie on /?some=query".to_owned()));
+
+        let response = client.delete("/").cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+
+        let response = client.post("/ex1").cookie(Cookie::new("some", "cookie")).dispatch(); //exceptions still apply
+        assert_eq!(response.into_string(), Some("target-ex1".to_owned()));
+
+        let (token, cookie) = get_token(&client);
+        let response = post_token(&client, "/".to_owned(), token, cookie).dispatch();
+        assert_eq!(response.into_string(), Some("success".to_owned()));
+
+        let rocket = default_rocket(default_builder().set_reject_status(Status::BadRequest).finalize().unwrap());
+        let client = Client::tracked(rocket).expect("valid rocket instance");
+
+        let response = client.post("/").cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+
+    #[test]
+    fn test_violation_reason() {
+        let builder = || {
+            default_builder()
+                .set_secret([0; 32])
+                .set_default_target("/csrf-reason".to_owned(), Method::Get)
+        };
+        let rocket = default_rocket(builder().finalize().unwrap());
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        let rocket2 = default_rocket(builder().finalize().unwrap());
+        let client2 = Client::untracked(rocket2).expect("valid rocket instance");
+
+        let (token, cookie) = get_token(&client);
+        let (token2, _cookie2) = get_token(&client2);
+
+        let reason = |token: &str, cookie: &str| {
+            post_token(&client, "/".to_owned(), token.to_owned(), cookie.to_owned())
+                .cookie(Cookie::new("some", "cookie"))
+                .dispatch()
+                .into_string()
+                .unwrap()
+        };
+        assert_eq!(reason(&token, &cookie), "success");
+        assert_eq!(reason(&token, ""), "MissingCookie");
+        assert_eq!(reason(&token, "!!!"), "UndecodableCookie");
+        assert_eq!(reason(&token, "AAAA"), "InvalidCookie");
+        assert_eq!(reason("", &cookie), "MissingToken");
+        assert_eq!(reason("!!!", &cookie), "UndecodableToken");
+        assert_eq!(reason("AAAA", &cookie), "InvalidToken");
+        assert_eq!(reason(&token2, &cookie), "TokenMismatch");
+
+        let rocket = default_rocket(builder().set_timeout(0).finalize().unwrap());
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let (token, cookie) = get_token(&client);
+        let response = post_token(&client, "/".to_owned(), token, cookie).dispatch();
+        assert_eq!(response.into_string(), Some("ExpiredCookie".to_owned()));
+
+        let rocket = default_rocket(builder().set_check_mode(CheckMode::OriginOnly).finalize().unwrap());
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let response = client.post("/").cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.into_string(), Some("InvalidOrigin".to_owned()));
+
+        let response = client.get("/csrf-reason").dispatch(); //not a violation
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn test_token_insertion() {
+        let rocket = default_rocket(
+            default_builder()
+                .set_auto_insert_disable_prefix(vec!["/static".to_owned()])
+                .finalize()
+                .unwrap(),
+        );
+        let client = Client::tracked(rocket).expect("valid rocket instance");
+
+        let response = client
+            .get("/")
+            .cookie(Cookie::new("some", "cookie"))
+            .dispatch(); //token well inserted
+        assert!(
+            response.into_string().unwrap().len()
+                > "<div><form method='POST'></form></div>".len()
+                    + "<input type=\"hidden\" name=\"csrf-token\" value=\"\"/>".len()
+        );
+
+        let response = client
+            .get("/static/something")
+            .cookie(Cookie::new("some", "cookie"))
+            .dispatch(); //url well ignored by token inserter
+        assert_eq!(
+            response.into_string(),
+            Some("<div><form method='POST'></form></div>".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_scoped_tokens() {
+        let rocket = default_rocket(default_builder().set_scoped_tokens(true).finalize().unwrap());
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let response = client.get("/forms").cookie(Cookie::new("some", "cookie")).dispatch();
+        let cookie = response.cookies().get(CSRF_COOKIE_NAME).unwrap().value().to_owned();
+        let body = response.into_string().unwrap();
+        let tokens = body
+            .split("name=\"csrf-token\" value=\"")
+            .skip(1)
+            .map(|part| part.split('"').next().unwrap().to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(tokens.len(), 2);
+        assert_ne!(tokens[0], tokens[1]);
+
+        let send = |path: &str, body: String| {
+            client
+                .post(path.to_owned())
+                .header(ContentType::Form)
+                .body(body)
+                .cookie(Cookie::new(CSRF_COOKIE_NAME, cookie.clone()))
+                .dispatch()
+                .into_string()
+        };
+        //each token is only valid for the action and method of its form
+        assert_eq!(send("/forms", format!("csrf-token={}", tokens[0])), Some("forms".to_owned()));
+        assert_eq!(send("/", format!("csrf-token={}", tokens[0])), Some("violation".to_owned()));
+        assert_eq!(send("/", format!("_method=delete&csrf-token={}", tokens[1])), Some("deleted".to_owned()));
+        assert_eq!(send("/", format!("csrf-token={}", tokens[1])), Some("violation".to_owned()));
+
+        //tokens from the request guard are not scoped
+        let (token, cookie) = get_token(&client);
+        let response = post_token(&client, "/".to_owned(), token, cookie).dispatch();
+        assert_eq!(response.into_string(), Some("success".to_owned()));
+
+        //unless required, in which case they are only accepted in headers
+        let rocket = default_rocket(
+            default_builder()
+                .set_scoped_tokens(true)
+                .set_require_scoped_form_tokens(true)
+                .finalize()
+                .unwrap(),
+        );
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        let (token, cookie) = get_token(&client);
+        let response = post_token(&client, "/".to_owned(), token.clone(), cookie.clone()).dispatch();
+        assert_eq!(response.into_string(), Some("violation".to_owned()));
+        let response = client
+            .post("/")
+            .header(Header::new("X-CSRF-Token", token))
+            .cookie(Cookie::new(CSRF_COOKIE_NAME, cookie))
+            .dispatch();
+        assert_eq!(response.into_string(), Some("success".to_owned()));
+
+        let response = client.get("/forms").cookie(Cookie::new("some", "cookie")).dispatch();
+        let cookie = response.cookies().get(CSRF_COOKIE_NAME).unwrap().value().to_owned();
+        let body = response.into_string().unwrap();
+        let scoped = body.split("value=\"").nth(1).unwrap().split('"').next().unwrap().to_owned();
+        let response = client
+            .post("/forms")
+            .header(ContentType::Form)
+            .body(format!("csrf-token={}", scoped))
+            .cookie(Cookie::new(CSRF_COOKIE_NAME, cookie))
+            .dispatch();
+        assert_eq!(response.into_string(), Some("forms".to_owned()));
+    }
+
+    #[test]
+    fn test_one_time_tokens() {
+        let rocket = default_rocket(
+            default_builder()
+                .set_default_target("/csrf-reason".to_owned(), Method::Get)
+                .set_one_time_tokens(true)
+                .finalize()
+                .unwrap(),
+        );
+        let client = Client::untracked(rocket).expect("valid rocket instance");
 
-        let rocket1 = default_rocket(default_builder().finalize().unwrap());
-        let client1 = Client::new(rocket1).expect("valid rocket instance");
-        let rocket2 = default_rocket(default_builder().finalize().unwrap());
-        let client2 = Client::new(rocket2).expect("valid rocket instance");
+        let (token, cookie) = get_token(&client);
+        let response = post_token(&client, "/".to_owned(), token.clone(), cookie.clone()).dispatch();
+        assert_eq!(response.into_string(), Some("success".to_owned()));
+        let response = post_token(&client, "/".to_owned(), token, cookie).dispatch();
+        assert_eq!(response.into_string(), Some("ReusedToken".to_owned()));
+
+        let (token, cookie) = get_token(&client); //each token has its own id
+        let response = post_token(&client, "/".to_owned(), token, cookie).dispatch();
+        assert_eq!(response.into_string(), Some("success".to_owned()));
+
+        //tokens expire with their use record, even if the cookie is refreshed in the meantime
+        let rocket = default_rocket(
+            default_builder()
+                .set_default_target("/csrf-reason".to_owned(), Method::Get)
+                .set_one_time_tokens(true)
+                .set_timeout(2)
+                .finalize()
+                .unwrap(),
+        );
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        let (token, cookie) = get_token(&client);
+        let response = post_token(&client, "/".to_owned(), token.clone(), cookie.clone()).dispatch();
+        assert_eq!(response.into_string(), Some("success".to_owned()));
+        let response = post_token(&client, "/".to_owned(), token.clone(), cookie.clone()).dispatch();
+        assert_eq!(response.into_string(), Some("ReusedToken".to_owned()));
+        ::std::thread::sleep(::std::time::Duration::from_secs(3));
+        let response = client
+            .get("/token")
+            .cookie(Cookie::new("some", "cookie"))
+            .cookie(Cookie::new(CSRF_COOKIE_NAME, cookie))
+            .dispatch();
+        let refreshed = response.cookies().get(CSRF_COOKIE_NAME).unwrap().value().to_owned();
+        let response = post_token(&client, "/".to_owned(), token, refreshed).dispatch();
+        assert_eq!(response.into_string(), Some("ExpiredToken".to_owned()));
+
+        struct FailingStore;
 
-        let (_token, _cookie) = get_token(&client1);
-        let (token2, cookie2) = get_token(&client2);
+        #[rocket::async_trait]
+        impl ReplayStore for FailingStore {
+            async fn consume(&self, _id: &[u8], _ttl: u64) -> bool {
+                false
+            }
+        }
+
+        let rocket = default_rocket(default_builder().set_replay_store(FailingStore).finalize().unwrap());
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let (token, cookie) = get_token(&client);
+        let response = post_token(&client, "/".to_owned(), token, cookie).dispatch();
+        assert_eq!(response.into_string(), Some("violation".to_owned()));
+    }
+
+    #[test]
+    fn test_stateless_tokens() {
+        assert_eq!(
+            default_builder().set_token_mode(TokenMode::Stateless).finalize().err(),
+            Some(ConfigError::MissingSessionBinder)
+        );
+
+        let builder = || {
+            default_builder()
+                .set_default_target("/csrf-reason".to_owned(), Method::Get)
+                .set_token_mode(TokenMode::Stateless)
+                .set_session_binder(|request: &Request<'_>| {
+                    request.cookies().get("user").map(|cookie| cookie.value().as_bytes().to_vec())
+                })
+        };
+        let rocket = default_rocket(builder().finalize().unwrap());
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let response = client.get("/token").cookie(Cookie::new("user", "alice")).dispatch();
+        assert!(response.cookies().get(CSRF_COOKIE_NAME).is_none());
+        let token = response.into_string().unwrap();
+        let post = |token: &str, user: &str| {
+            post_token(&client, "/".to_owned(), token.to_owned(), String::new())
+                .cookie(Cookie::new("user", user.to_owned()))
+                .dispatch()
+                .into_string()
+                .unwrap()
+        };
+        assert_eq!(post(&token, "alice"), "success");
+        assert_eq!(post(&token, "bob"), "InvalidToken");
+        assert_eq!(post("", "alice"), "MissingToken");
+        let response = client.post("/").cookie(Cookie::new("some", "cookie")).dispatch(); //no session
+        assert_eq!(response.into_string(), Some("success".to_owned()));
+
+        //tokens are also inserted in forms
+        let response = client.get("/").cookie(Cookie::new("user", "alice")).dispatch();
+        let body = response.into_string().unwrap();
+        let token = body.split("value=\"").nth(1).unwrap().split('"').next().unwrap();
+        assert_eq!(post(token, "alice"), "success");
+
+        let rocket = default_rocket(builder().set_timeout(0).finalize().unwrap());
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let token = client.get("/token").cookie(Cookie::new("user", "alice")).dispatch().into_string().unwrap();
+        let response = post_token(&client, "/".to_owned(), token, String::new())
+            .cookie(Cookie::new("user", "alice"))
+            .dispatch();
+        assert_eq!(response.into_string(), Some("ExpiredToken".to_owned()));
+    }
+
+    #[test]
+    fn test_stored_tokens() {
+        assert_eq!(
+            default_builder().set_token_mode(TokenMode::Stored).finalize().err(),
+            Some(ConfigError::MissingSessionBinder)
+        );
+
+        let store = Arc::new(MemoryTokenStore::new());
+        let builder = |store: Arc<MemoryTokenStore>| {
+            default_builder()
+                .set_default_target("/csrf-reason".to_owned(), Method::Get)
+                .set_token_store(store)
+                .set_session_binder(|request: &Request<'_>| {
+                    request.cookies().get("user").map(|cookie| cookie.value().as_bytes().to_vec())
+                })
+        };
+        let rocket = default_rocket(builder(store.clone()).set_scoped_tokens(true).finalize().unwrap());
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        let get = |path: &str, user: &str| {
+            client.get(path.to_owned()).cookie(Cookie::new("user", user.to_owned())).dispatch()
+        };
+        let post = |path: &str, body: String, user: &str| {
+            client
+                .post(path.to_owned())
+                .header(ContentType::Form)
+                .body(body)
+                .cookie(Cookie::new("user", user.to_owned()))
+                .dispatch()
+                .into_string()
+                .unwrap()
+        };
+
+        let response = get("/token", "alice");
+        assert!(response.cookies().get(CSRF_COOKIE_NAME).is_none());
+        let token = response.into_string().unwrap();
+        let other = get("/token", "alice").into_string().unwrap();
+        assert_ne!(other, token); //fresh tokens are reused, but masked differently in each page
+        assert_eq!(post("/", format!("csrf-token={}", other), "alice"), "success");
+        assert_eq!(post("/", format!("csrf-token={}", token), "alice"), "success");
+        assert_eq!(post("/", format!("csrf-token={}", token), "bob"), "InvalidToken");
+        let response = client.post("/").cookie(Cookie::new("some", "cookie")).dispatch(); //no session
+        assert_eq!(response.into_string(), Some("success".to_owned()));
+
+        //outstanding tokens can be audited
+        let outstanding = rocket::async_test(store.tokens(b"alice")).unwrap();
+        assert_eq!(outstanding.len(), 1);
+        let masked = BASE64URL_NOPAD.decode(token.as_bytes()).unwrap();
+        let (pad, masked) = masked.split_at(32);
+        assert_eq!(outstanding[0].0, pad.iter().zip(masked).map(|(p, m)| p ^ m).collect::<Vec<_>>());
+
+        //forms get scoped tokens derived from the stored one
+        let body = get("/forms", "alice").into_string().unwrap();
+        let scoped = body.split("value=\"").nth(1).unwrap().split('"').next().unwrap().to_owned();
+        assert_ne!(scoped, token);
+        assert_eq!(post("/forms", format!("csrf-token={}", scoped), "alice"), "forms");
+        assert_eq!(post("/", format!("csrf-token={}", scoped), "alice"), "InvalidToken");
+
+        //revoking a session invalidates all of its tokens
+        rocket::async_test(store.revoke(b"alice")).unwrap();
+        assert_eq!(post("/", format!("csrf-token={}", token), "alice"), "InvalidToken");
+        assert_eq!(post("/forms", format!("csrf-token={}", scoped), "alice"), "InvalidToken");
+        let token = get("/token", "alice").into_string().unwrap();
+        assert_eq!(post("/", format!("csrf-token={}", token), "alice"), "success");
+
+        //one-time tokens are removed from the store
+        let rocket = default_rocket(builder(Arc::new(MemoryTokenStore::new())).set_one_time_tokens(true).finalize().unwrap());
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        let post = |token: &str| {
+            post_token(&client, "/".to_owned(), token.to_owned(), String::new())
+                .cookie(Cookie::new("user", "alice"))
+                .dispatch()
+                .into_string()
+                .unwrap()
+        };
+        let token = client.get("/token").cookie(Cookie::new("user", "alice")).dispatch().into_string().unwrap();
+        assert_eq!(post(&token), "success");
+        assert_eq!(post(&token), "InvalidToken");
+        let next = client.get("/token").cookie(Cookie::new("user", "alice")).dispatch().into_string().unwrap();
+        assert_ne!(next, token);
+        assert_eq!(post(&next), "success");
+
+        //one-time scoped tokens of a page don't invalidate each other
+        let rocket = default_rocket(
+            builder(Arc::new(MemoryTokenStore::new()))
+                .set_one_time_tokens(true)
+                .set_scoped_tokens(true)
+                .finalize()
+                .unwrap(),
+        );
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        let body = client.get("/forms").cookie(Cookie::new("user", "alice")).dispatch().into_string().unwrap();
+        let tokens = body
+            .split("name=\"csrf-token\" value=\"")
+            .skip(1)
+            .map(|part| part.split('"').next().unwrap().to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(tokens.len(), 2);
+        let send = |path: &str, body: String| {
+            client
+                .post(path.to_owned())
+                .header(ContentType::Form)
+                .body(body)
+                .cookie(Cookie::new("user", "alice"))
+                .dispatch()
+                .into_string()
+                .unwrap()
+        };
+        assert_eq!(send("/", format!("_method=delete&csrf-token={}", tokens[1])), "deleted");
+        assert_eq!(send("/forms", format!("csrf-token={}", tokens[0])), "forms");
+        assert_eq!(send("/forms", format!("csrf-token={}", tokens[0])), "ReusedToken");
+    }
+
+    #[test]
+    fn test_masked_tokens() {
+        let builder = || {
+            default_builder()
+                .set_default_target("/csrf-reason".to_owned(), Method::Get)
+                .set_secret([1; 32])
+        };
+        let rocket = default_rocket(builder().set_masked_tokens(true).set_scoped_tokens(true).finalize().unwrap());
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let (token, cookie) = get_token(&client);
+        assert_eq!(token.len(), 86);
+        let response = client
+            .get("/token")
+            .cookie(Cookie::new("some", "cookie"))
+            .cookie(Cookie::new(CSRF_COOKIE_NAME, cookie.clone()))
+            .dispatch();
+        let other = response.into_string().unwrap();
+        assert_ne!(other, token); //a fresh pad is used at each request
+        for token in [&token, &other] {
+            let response = post_token(&client, "/".to_owned(), token.to_string(), cookie.clone()).dispatch();
+            assert_eq!(response.into_string(), Some("success".to_owned()));
+        }
+        let (_, other_cookie) = get_token(&client);
+        let response = post_token(&client, "/".to_owned(), token.clone(), other_cookie).dispatch();
+        assert_eq!(response.into_string(), Some("TokenMismatch".to_owned()));
+
+        //forms get scoped tokens holding the unmasked secret
+        let response = client.get("/forms").cookie(Cookie::new("some", "cookie")).dispatch();
+        let cookie = response.cookies().get(CSRF_COOKIE_NAME).unwrap().value().to_owned();
+        let body = response.into_string().unwrap();
+        let scoped = body.split("value=\"").nth(1).unwrap().split('"').next().unwrap().to_owned();
+        let send = |path: &str| {
+            client
+                .post(path.to_owned())
+                .header(ContentType::Form)
+                .body(format!("csrf-token={}", scoped))
+                .cookie(Cookie::new(CSRF_COOKIE_NAME, cookie.clone()))
+                .dispatch()
+                .into_string()
+        };
+        assert_eq!(send("/forms"), Some("forms".to_owned()));
+        assert_eq!(send("/"), Some("InvalidToken".to_owned()));
+
+        //encrypted tokens stay valid when masking is enabled
+        let rocket = default_rocket(builder().finalize().unwrap());
+        let (token, cookie) = get_token(&Client::untracked(rocket).expect("valid rocket instance"));
+        assert_eq!(token.len(), 139);
+        let response = post_token(&client, "/".to_owned(), token, cookie).dispatch();
+        assert_eq!(response.into_string(), Some("success".to_owned()));
+
+        //masked tokens can be masked again with any pad, so they can't be one-time tokens
+        assert_eq!(
+            builder().set_masked_tokens(true).set_one_time_tokens(true).finalize().err(),
+            Some(ConfigError::MaskedOneTimeTokens)
+        );
+        let (token, cookie) = get_token(&client);
+        let mut remasked = BASE64URL_NOPAD.decode(token.as_bytes()).unwrap();
+        remasked.iter_mut().for_each(|byte| *byte ^= 0x5a); //a new pad, masking the same secret
+        let remasked = BASE64URL_NOPAD.encode(&remasked);
+        assert_ne!(remasked, token);
+        let response = post_token(&client, "/".to_owned(), remasked.clone(), cookie.clone()).dispatch();
+        assert_eq!(response.into_string(), Some("success".to_owned()));
+        let rocket = default_rocket(builder().set_one_time_tokens(true).finalize().unwrap());
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        for token in [token, remasked] {
+            let response = post_token(&client, "/".to_owned(), token, cookie.clone()).dispatch();
+            assert_eq!(response.into_string(), Some("InvalidToken".to_owned()));
+        } //masked tokens are only accepted while masking is enabled
+    }
+
+    #[test]
+    fn test_auto_insert_disabled() {
+        let rocket = default_rocket(default_builder().set_auto_insert(false).finalize().unwrap());
+        let client = Client::tracked(rocket).expect("valid rocket instance");
+
+        let response = client
+            .get("/")
+            .cookie(Cookie::new("some", "cookie"))
+            .dispatch();
+        assert_eq!(
+            response.into_string(),
+            Some("<div><form method='POST'></form></div>".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_auto_insert_failing_body() {
+        let rocket = default_rocket(default_builder().finalize().unwrap());
+        let client = Client::tracked(rocket).expect("valid rocket instance");
+
+        let response = client
+            .get("/failing-body")
+            .cookie(Cookie::new("some", "cookie"))
+            .dispatch(); //an unreadable body is an error, not an empty page
+        assert_eq!(response.status(), Status::InternalServerError);
+    }
+
+    #[test]
+    fn test_auto_insert_stream() {
+        let rocket = default_rocket(
+            default_builder()
+                .set_auto_insert_max_chunk_size(1)
+                .finalize()
+                .unwrap(),
+        );
+        let client = Client::tracked(rocket).expect("valid rocket instance");
+
+        let response = client
+            .get("/")
+            .cookie(Cookie::new("some", "cookie"))
+            .dispatch(); //token well inserted
+        assert!(
+            response.into_string().unwrap().len()
+                > "<div><form method='POST'></form></div>".len()
+                    + "<input type=\"hidden\" name=\"csrf-token\" value=\"\"/>".len()
+        );
+
+        //streamed bodies are rewritten as they are read, even when tags are cut between reads
+        let response = client.get("/stream").cookie(Cookie::new("some", "cookie")).dispatch();
+        let cookie = response.cookies().get(CSRF_COOKIE_NAME).unwrap().value().to_owned();
+        let body = response.into_string().unwrap();
+        let token = body.split("value=\"").nth(1).unwrap().split('"').next().unwrap().to_owned();
+        assert_eq!(
+            body,
+            format!(
+                "<div><FORM method='POST'><input type=\"hidden\" name=\"csrf-token\" value=\"{}\"/><input name='name'></FORM></div>",
+                token
+            )
+        );
+        let response = post_token(&client, "/".to_owned(), token, cookie).cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.into_string(), Some("success".to_owned()));
+    }
+
+    #[test]
+    fn test_key_from_config() {
+        let key = "0404040404040404040404040404040404040404040404040404040404040404";
+        let figment = || rocket::Config::figment().merge(("secret_key", key));
+
+        let rocket1 = custom_rocket(figment(), default_builder().finalize().unwrap());
+        let client1 = Client::untracked(rocket1).expect("valid rocket instance");
+        let rocket2 = custom_rocket(figment(), default_builder().finalize().unwrap());
+        let client2 = Client::untracked(rocket2).expect("valid rocket instance");
+
+        let (token, cookie) = get_token(&client2);
 
         //client 1 and 2 should be compatible
-        let mut response =
-            post_token(&client1, "/".to_owned(), token2.clone(), cookie2.clone()).cookie(Cookie::new("some", "cookie")).dispatch();
-        assert_eq!(response.body_string(), Some("success".to_owned()));
+        let response =
+            post_token(&client1, "/".to_owned(), token.clone(), cookie.clone()).cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.into_string(), Some("success".to_owned()));
+
+        //the csrf key is derived, and differ from Rocket's own key
+        let rocket3 = default_rocket(default_builder().set_secret([4; 32]).finalize().unwrap());
+        let client3 = Client::untracked(rocket3).expect("valid rocket instance");
+        let response =
+            post_token(&client3, "/".to_owned(), token, cookie).cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.into_string(), Some("violation".to_owned()));
+    }
+
+    #[test]
+    fn test_secret_key_rotation() {
+        let old_key = "0404040404040404040404040404040404040404040404040404040404040404";
+        let new_key = vec![5u8; 64];
+        let old_rocket = custom_rocket(
+            rocket::Config::figment().merge(("secret_key", old_key)),
+            default_builder().finalize().unwrap(),
+        );
+        let (token, cookie) = get_token(&Client::untracked(old_rocket).expect("valid rocket instance"));
+        let post = |rocket| {
+            let client = Client::untracked(rocket).expect("valid rocket instance");
+            let response = post_token(&client, "/".to_owned(), token.clone(), cookie.clone())
+                .cookie(Cookie::new("some", "cookie"))
+                .dispatch();
+            response.into_string().unwrap()
+        };
+        let figment = || rocket::Config::figment().merge(("secret_key", new_key.clone()));
+
+        assert_eq!(post(custom_rocket(figment(), default_builder().finalize().unwrap())), "violation");
+        let rocket = custom_rocket(
+            figment(),
+            default_builder()
+                .set_previous_secret_keys(vec![HEXLOWER_PERMISSIVE.decode(old_key.as_bytes()).unwrap()])
+                .finalize()
+                .unwrap(),
+        );
+        assert_eq!(post(rocket), "success");
+
+        //previous keys may also be configured, in the same formats as `secret_key`
+        let toml = format!("[default.csrf]\nprevious_secret_keys = [\"{}\"]", old_key);
+        let figment = figment().merge(Toml::string(&toml).nested());
+        assert_eq!(post(custom_rocket(figment, default_builder().finalize().unwrap())), "success");
+    }
+
+    #[test]
+    fn test_key_required_in_release() {
+        let release = || rocket::Config::figment().select(rocket::Config::RELEASE_PROFILE);
+
+        let failed_ignition = |rocket| match Client::untracked(rocket) {
+            Err(e) => matches!(e.kind(), ErrorKind::FailedFairings(_)),
+            Ok(_) => false,
+        };
+
+        let rocket = custom_rocket(release(), default_builder().finalize().unwrap());
+        assert!(failed_ignition(rocket));
+
+        let rocket = custom_rocket(
+            release().merge(("secret_key", [7u8; 32])),
+            default_builder().finalize().unwrap(),
+        );
+        assert!(Client::untracked(rocket).is_ok());
+
+        let rocket = custom_rocket(release(), default_builder().set_secret([7; 32]).finalize().unwrap());
+        assert!(Client::untracked(rocket).is_ok());
+
+        //an invalid key is refused in any profile
+        let rocket = custom_rocket(
+            rocket::Config::figment().merge(("secret_key", "too short")),
+            default_builder().finalize().unwrap(),
+        );
+        assert!(failed_ignition(rocket));
+    }
+
+    #[test]
+    fn test_config() {
+        let toml = r#"
+            [default.csrf]
+            default_target = { path = "/csrf-reason" }
+            exceptions = [{ source = "/guarded", destination = "/ex1-target", method = "POST" }]
+            auto_insert = false
+        "#;
+        let figment = rocket::Config::figment().merge(Toml::string(toml).nested());
+        let rocket = custom_rocket(figment, default_builder().finalize().unwrap());
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        //default target is replaced
+        let response = client.post("/").cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.into_string(), Some("MissingCookie".to_owned()));
+
+        //exceptions are added to the builder's ones
+        let response = client.post("/guarded").cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.into_string(), Some("target-ex1".to_owned()));
+        let response = client.post("/ex1").cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.into_string(), Some("target-ex1".to_owned()));
+
+        //tokens are no longer inserted
+        let response = client.get("/").cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(
+            response.into_string(),
+            Some("<div><form method='POST'></form></div>".to_owned())
+        );
+
+        //invalid configuration fails ignition
+        for toml in &[
+            "[default.csrf]\ndefault_target = { path = \"/<invalid>\" }",
+            "[default.csrf]\nduration = \"long\"",
+            "[default.csrf]\nunknown = true",
+            "[default.csrf]\nexceptions = [{ source = \"/a\", destination = \"/<b>\" }]",
+        ] {
+            let figment = rocket::Config::figment().merge(Toml::string(toml).nested());
+            let rocket = custom_rocket(figment, default_builder().finalize().unwrap());
+            match Client::untracked(rocket) {
+                Err(e) => assert!(matches!(e.kind(), ErrorKind::FailedFairings(_))),
+                Ok(_) => panic!("invalid configuration was accepted"),
+            }
+        }
     }
 
     #[test]
@@ -813,43 +3153,254 @@ How are you?
         );
     }
 
+    #[test]
+    fn test_route_exceptions() {
+        let rocket = default_rocket(
+            default_builder()
+                .add_route_exceptions(routes![route_ex_dynamic], "/route-ex-target/<name>".to_owned(), Some(Method::Get))
+                .finalize()
+                .unwrap(),
+        );
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let response = client.post("/route-ex/something").cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.into_string(), Some("something".to_owned()));
+
+        //Rocket route this to another route, so the exception doesn't apply
+        let response = client.post("/route-ex/static").cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.into_string(), Some("violation".to_owned()));
+
+        let rocket = default_rocket(
+            default_builder()
+                .add_named_exceptions(vec![(
+                    "route_ex_static".to_owned(),
+                    "/route-ex-target/named".to_owned(),
+                    Some(Method::Get),
+                )])
+                .finalize()
+                .unwrap(),
+        );
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let response = client.post("/route-ex/static?with=query").cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.into_string(), Some("named".to_owned()));
+        let response = client.post("/route-ex/something").cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.into_string(), Some("violation".to_owned()));
+
+        //unknown routes and uncaptured parts fail ignition
+        let builders = vec![
+            default_builder().add_named_exceptions(vec![("missing".to_owned(), "/".to_owned(), None)]),
+            default_builder().add_route_exceptions(routes![route_ex_dynamic], "/<other>".to_owned(), None),
+        ];
+        for builder in builders {
+            let rocket = default_rocket(builder.finalize().unwrap());
+            match Client::untracked(rocket) {
+                Err(e) => assert!(matches!(e.kind(), ErrorKind::FailedFairings(_))),
+                Ok(_) => panic!("invalid route exception was accepted"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_exempt_routes() {
+        let rocket = default_rocket(default_builder().add_exempt_routes(routes![webhook]).finalize().unwrap());
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let response = client.post("/webhook").cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.into_string(), Some("webhook".to_owned()));
+        let response = client.post("/").cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.into_string(), Some("violation".to_owned()));
+
+        //exempt routes can also be named in configuration
+        let toml = "[default.csrf]\nexempt_routes = [\"post_index\"]";
+        let figment = rocket::Config::figment().merge(Toml::string(toml).nested());
+        let rocket = custom_rocket(figment, default_builder().add_exempt_route_names(vec!["webhook".to_owned()]).finalize().unwrap());
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let response = client.post("/webhook").cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.into_string(), Some("webhook".to_owned()));
+        let response = client.post("/").cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.into_string(), Some("success".to_owned()));
+        let response = client.delete("/").cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.into_string(), Some("violation".to_owned()));
+
+        let rocket = default_rocket(default_builder().add_exempt_route_names(vec!["missing".to_owned()]).finalize().unwrap());
+        match Client::untracked(rocket) {
+            Err(e) => assert!(matches!(e.kind(), ErrorKind::FailedFairings(_))),
+            Ok(_) => panic!("unknown exempt route was accepted"),
+        }
+    }
+
+    #[test]
+    fn test_protected_methods() {
+        let rocket = default_rocket(default_builder().set_protected_methods(vec![Method::Post]).finalize().unwrap());
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let response = client.delete("/").cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.into_string(), Some("deleted".to_owned()));
+        let response = client.post("/").cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.into_string(), Some("violation".to_owned()));
+
+        let toml = "[default.csrf]\nprotected_methods = [\"DELETE\"]";
+        let figment = rocket::Config::figment().merge(Toml::string(toml).nested());
+        let rocket = custom_rocket(figment, default_builder().finalize().unwrap());
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let response = client.delete("/").cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.into_string(), Some("violation".to_owned()));
+        let response = client.post("/").cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.into_string(), Some("success".to_owned()));
+    }
+
+    #[test]
+    fn test_method_exceptions() {
+        let rocket = default_rocket(
+            default_builder()
+                .add_method_exceptions(vec![(Method::Delete, "/".to_owned(), "/".to_owned(), None)])
+                .finalize()
+                .unwrap(),
+        );
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let response = client.delete("/").cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.into_string(), Some("deleted".to_owned()));
+        let response = client.post("/").cookie(Cookie::new("some", "cookie")).dispatch(); //same path, other method
+        assert_eq!(response.into_string(), Some("violation".to_owned()));
+
+        let toml = "[default.csrf]\nexceptions = [{ source = \"/\", source_method = \"POST\", destination = \"/\" }]";
+        let figment = rocket::Config::figment().merge(Toml::string(toml).nested());
+        let rocket = custom_rocket(figment, default_builder().finalize().unwrap());
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let response = client.post("/").cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.into_string(), Some("success".to_owned()));
+        let response = client.delete("/").cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.into_string(), Some("violation".to_owned()));
+    }
+
+    #[test]
+    fn test_method_override() {
+        let rocket = default_rocket(default_builder().finalize().unwrap());
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        let (token, cookie) = get_token(&client);
+
+        let response = client
+            .post("/")
+            .header(ContentType::Form)
+            .body("_method=delete")
+            .cookie(Cookie::new("some", "cookie"))
+            .dispatch();
+        assert_eq!(response.into_string(), Some("violation".to_owned()));
+
+        let response = client
+            .post("/")
+            .header(ContentType::Form)
+            .body(format!("_method=delete&{}={}", CSRF_FORM_FIELD, token))
+            .cookie(Cookie::new(CSRF_COOKIE_NAME, cookie))
+            .dispatch();
+        assert_eq!(response.into_string(), Some("deleted".to_owned()));
+
+        //violations and exceptions see the overridden method
+        let rocket = default_rocket(
+            default_builder()
+                .set_default_target("/csrf-method?method=<method>".to_owned(), Method::Get)
+                .add_method_exceptions(vec![(Method::Put, "/".to_owned(), "/ex1-target".to_owned(), Some(Method::Post))])
+                .finalize()
+                .unwrap(),
+        );
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let response = client
+            .post("/")
+            .header(ContentType::Form)
+            .body("_method=DELETE")
+            .cookie(Cookie::new("some", "cookie"))
+            .dispatch();
+        assert_eq!(response.into_string(), Some("DELETE".to_owned()));
+        let response = client
+            .post("/")
+            .header(ContentType::Form)
+            .body("_method=put")
+            .cookie(Cookie::new("some", "cookie"))
+            .dispatch();
+        assert_eq!(response.into_string(), Some("target-ex1".to_owned()));
+        let response = client.post("/").cookie(Cookie::new("some", "cookie")).dispatch();
+        assert_eq!(response.into_string(), Some("POST".to_owned()));
+    }
+
+    #[test]
+    fn test_invalid_exceptions() {
+        let err = default_builder()
+            .add_exceptions(vec![("/a/<b>".to_owned(), "/c/<d>".to_owned(), None)])
+            .finalize()
+            .err();
+        assert_eq!(
+            err,
+            Some(ConfigError::UncapturedParam {
+                source: "/a/<b>".to_owned(),
+                destination: "/c/<d>".to_owned(),
+                param: "d".to_owned(),
+            })
+        );
+
+        let err = default_builder()
+            .add_exceptions(vec![("/a/<b../c".to_owned(), "/".to_owned(), None)])
+            .finalize()
+            .err();
+        match err {
+            Some(ConfigError::InvalidPath { path, error }) => {
+                assert_eq!(path, "/a/<b../c");
+                assert_eq!(error.kind, PathErrorKind::Malformed);
+                assert_eq!(error.position, 3);
+                assert_eq!(error.segment, "<b..");
+            }
+            _ => panic!("invalid path was accepted"),
+        }
+
+        assert!(
+            default_builder()
+                .add_exceptions(vec![("/a/<b>?c=<d>".to_owned(), "/e/<d>?f=<b>".to_owned(), None)])
+                .finalize()
+                .is_ok()
+        );
+    }
+
     #[test]
     fn test_insert_only_on_session() {
         let rocket = default_rocket(default_builder().finalize().unwrap());
-        let client = Client::new(rocket).expect("valid rocket instance");
+        let client = Client::tracked(rocket).expect("valid rocket instance");
 
-        let mut response = client.get("/").dispatch();
-        assert_eq!(response.body_string().unwrap(), "<div><form method='POST'></form></div>");
+        let response = client.get("/").dispatch();
         assert!(response.headers().get("set-cookie").next().is_none()); // nothing inserted if no session detected
+        assert_eq!(response.into_string().unwrap(), "<div><form method='POST'></form></div>");
 
-        let mut response = client
+        let response = client
             .get("/")
             .cookie(Cookie::new(CSRF_COOKIE_NAME, ""))
             .dispatch();
-        assert_eq!(response.body_string().unwrap(), "<div><form method='POST'></form></div>");
         assert!(
             response
                 .headers()
                 .get_one("set-cookie")
                 .unwrap()
                 .contains("Max-Age=0")
-        ) // delete cookie if no longer in session
+        ); // delete cookie if no longer in session
+        assert_eq!(response.into_string().unwrap(), "<div><form method='POST'></form></div>");
     }
 
     #[test]
     fn test_allow_request_without_session() {
         let rocket = default_rocket(default_builder().finalize().unwrap());
-        let client = Client::new(rocket).expect("valid rocket instance");
+        let client = Client::tracked(rocket).expect("valid rocket instance");
 
-        let mut response = client.post("/").dispatch();
-        assert_eq!(response.body_string().unwrap(), "success");
+        let response = client.post("/").dispatch();
+        assert_eq!(response.into_string().unwrap(), "success");
     }
 
     //Routes for above test
     #[get("/")]
-    fn index() -> ::rocket::response::content::Content<&'static str> {
-        ::rocket::response::content::Content(
-            ::rocket::http::ContentType::HTML,
+    fn index() -> ::rocket::response::content::RawHtml<&'static str> {
+        ::rocket::response::content::RawHtml(
             "<div><form method='POST'></form></div>",
         )
     }
@@ -859,16 +3410,58 @@ How are you?
         "success"
     }
 
+    #[delete("/")]
+    fn delete_index() -> &'static str {
+        "deleted"
+    }
+
+    #[post("/guarded")]
+    fn guarded(_csrf: VerifiedCsrf) -> &'static str {
+        "guarded"
+    }
+
+    #[post("/guarded-reason")]
+    fn guarded_reason(csrf: Result<VerifiedCsrf, CsrfViolation>) -> String {
+        match csrf {
+            Ok(_) => "guarded".to_owned(),
+            Err(violation) => violation.to_string(),
+        }
+    }
+
+    #[catch(403)]
+    fn forbidden(request: &Request) -> String {
+        let violation = CsrfViolation::of(request).unwrap();
+        format!("{} on {}", violation, CsrfViolation::uri_of(request).unwrap())
+    }
+
     #[get("/token")]
     fn token(t: CsrfToken) -> String {
         ::std::str::from_utf8(t.value()).unwrap().to_owned()
     }
 
+    #[get("/token-page")]
+    fn token_page(t: CsrfToken) -> ::rocket::response::content::RawHtml<String> {
+        ::rocket::response::content::RawHtml(format!(
+            "<form method='POST' action='/'></form><p>{}</p>",
+            ::std::str::from_utf8(t.value()).unwrap()
+        ))
+    }
+
     #[get("/csrf")]
     fn csrf() -> &'static str {
         "violation"
     }
 
+    #[get("/csrf-reason")]
+    fn csrf_reason(violation: CsrfViolation) -> String {
+        format!("{:?}", violation)
+    }
+
+    #[get("/csrf-method?<method>")]
+    fn csrf_method(method: String) -> String {
+        method
+    }
+
     #[get("/ex1")]
     fn get_ex1() -> &'static str {
         "get-ex1"
@@ -894,11 +3487,120 @@ How are you?
         pathpart
     }
 
+    #[post("/route-ex/static")]
+    fn route_ex_static() -> &'static str {
+        "route-ex-static"
+    }
+
+    #[post("/route-ex/<name>")]
+    fn route_ex_dynamic(name: String) -> String {
+        name
+    }
+
+    #[get("/route-ex-target/<value>")]
+    fn route_ex_target(value: String) -> String {
+        value
+    }
+
+    #[post("/webhook")]
+    fn webhook() -> &'static str {
+        "webhook"
+    }
+
+    #[get("/forms")]
+    fn forms() -> ::rocket::response::content::RawHtml<&'static str> {
+        ::rocket::response::content::RawHtml(
+            "<form method='POST'></form><form method='POST' action='/'><input name='_method' value='delete'></form>",
+        )
+    }
+
+    #[post("/forms")]
+    fn post_forms() -> &'static str {
+        "forms"
+    }
+
     #[get("/static/something")]
-    fn static_route() -> ::rocket::response::content::Content<&'static str> {
-        ::rocket::response::content::Content(
-            ::rocket::http::ContentType::HTML,
+    fn static_route() -> ::rocket::response::content::RawHtml<&'static str> {
+        ::rocket::response::content::RawHtml(
             "<div><form method='POST'></form></div>",
         )
     }
+
+    struct FailingBody;
+
+    impl tokio::io::AsyncRead for FailingBody {
+        fn poll_read(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            _buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Err(std::io::Error::other("broken body")))
+        }
+    }
+
+    impl tokio::io::AsyncSeek for FailingBody {
+        fn start_seek(self: std::pin::Pin<&mut Self>, _position: std::io::SeekFrom) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn poll_complete(self: std::pin::Pin<&mut Self>, _cx: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<u64>> {
+            std::task::Poll::Ready(Ok(0))
+        }
+    }
+
+    impl<'r> ::rocket::response::Responder<'r, 'static> for FailingBody {
+        fn respond_to(self, _request: &'r Request<'_>) -> ::rocket::response::Result<'static> {
+            Response::build()
+                .header(ContentType::HTML)
+                .sized_body(Some(16), self)
+                .ok()
+        }
+    }
+
+    #[get("/failing-body")]
+    fn failing_body() -> FailingBody {
+        FailingBody
+    }
+
+    /// Body of unknown size, read one byte at a time, and only every other time it is polled.
+    struct ByteStream {
+        data: &'static [u8],
+        ready: bool,
+    }
+
+    impl tokio::io::AsyncRead for ByteStream {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            self.ready = !self.ready;
+            if !self.ready {
+                cx.waker().wake_by_ref();
+                return std::task::Poll::Pending;
+            }
+            if let Some((&byte, rest)) = self.data.split_first() {
+                buf.put_slice(&[byte]);
+                self.data = rest;
+            }
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    impl<'r> ::rocket::response::Responder<'r, 'static> for ByteStream {
+        fn respond_to(self, _request: &'r Request<'_>) -> ::rocket::response::Result<'static> {
+            Response::build()
+                .header(ContentType::HTML)
+                .streamed_body(self)
+                .ok()
+        }
+    }
+
+    #[get("/stream")]
+    fn stream() -> ByteStream {
+        ByteStream {
+            data: b"<div><FORM method='POST'><input name='name'></FORM></div>",
+            ready: false,
+        }
+    }
 }
@@ -0,0 +1,58 @@
+use rocket::http::Status;
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest};
+use rocket::Request;
+
+use crate::csrf_violation::CsrfViolation;
+
+/// Request guard requiring a request to pass csrf verification.
+///
+/// The `VerifiedCsrf` type allow you to protect only some routes, by adding it as a parameter of
+/// their handler, instead of relying on the fairing redirecting every violation. It fails with
+/// `403 Forbidden` and a [CsrfViolation](enum.CsrfViolation.html) if the request did not pass the
+/// checks configured on the fairing, which must still be attached to Rocket. It is mostly usefull
+/// with [`set_enforce_globally`] set to false.
+///
+/// Requests whose method is not protected, see [`set_protected_methods`], and requests without
+/// session (without cookies, unless tokens are cookieless) are always considered valid. Requests
+/// to exempt routes, see [`add_exempt_routes`], are not verified at all, so this guard fails on
+/// them with `500 Internal Server Error` and [`CsrfViolation::NotVerified`].
+///
+/// [`set_enforce_globally`]: struct.CsrfFairingBuilder.html#method.set_enforce_globally
+/// [`set_protected_methods`]: struct.CsrfFairingBuilder.html#method.set_protected_methods
+/// [`add_exempt_routes`]: struct.CsrfFairingBuilder.html#method.add_exempt_routes
+/// [`CsrfViolation::NotVerified`]: enum.CsrfViolation.html#variant.NotVerified
+#[derive(Debug, Clone, Copy)]
+pub struct VerifiedCsrf;
+
+/// Verdict of the fairing on a request, stored in request-local cache.
+pub enum CsrfVerdict {
+    Unverified,
+    Valid,
+    Violation(CsrfViolation),
+}
+
+impl From<Result<(), CsrfViolation>> for CsrfVerdict {
+    fn from(verdict: Result<(), CsrfViolation>) -> Self {
+        match verdict {
+            Ok(()) => CsrfVerdict::Valid,
+            Err(violation) => CsrfVerdict::Violation(violation),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for VerifiedCsrf {
+    type Error = CsrfViolation;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, CsrfViolation> {
+        match request.local_cache(|| CsrfVerdict::Unverified) {
+            CsrfVerdict::Valid => Outcome::Success(VerifiedCsrf),
+            CsrfVerdict::Violation(violation) => Outcome::Error((Status::Forbidden, *violation)),
+            CsrfVerdict::Unverified => Outcome::Error((
+                Status::InternalServerError,
+                CsrfViolation::NotVerified,
+            )), //the fairing did not run on this request
+        }
+    }
+}
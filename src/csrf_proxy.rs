@@ -1,7 +1,10 @@
-use csrf_proxy::ParseState::*;
+use crate::csrf_proxy::ParseState::*;
 use std::cmp;
 use std::collections::VecDeque;
-use std::io::{Error, Read};
+use std::io::Error;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, ReadBuf};
 
 #[derive(Debug)]
 struct Buffer {
@@ -63,8 +66,7 @@ impl Default for Buffer {
 enum ParseState {
     Init,                    //default state
     PartialFormMatch,        //when parsing "<form"
-    SearchMethodForm,        //when searching for a method=\"POST\" tag
-    MatchingMethodForm,      //when matching against method=\"POST\"
+    FormTag,                 //when waiting for the end of "<form ...>", to read its attributes
     SearchFormElem,          //like default state, but inside a form
     PartialFormElemMatch,    //when parsing "<input", "<textarea" or other form elems, and "</form"
     SearchMethod(usize),     //when inside the first <input>, search for begining of a param
@@ -72,9 +74,22 @@ enum ParseState {
     CloseInputTag,           //only if insert after, search for '>' of a "<input name=\"_method\">"
 }
 
+/// Attributes of the form being parsed, to which scoped tokens are bound.
+#[derive(Debug, Default)]
+struct Form {
+    action: Option<String>,
+    multipart: bool,
+    method: Option<String>, //value of a "_method" input, if any
+}
+
+type ScopedToken<'a> = Box<dyn Fn(&str, &str) -> Option<Vec<u8>> + Send + 'a>;
+
 pub struct CsrfProxy<'a> {
-    underlying: Box<dyn Read + 'a>, //the underlying Reader from which we get data
-    token: Vec<u8>,                 //a full input tag loaded with a valid token
+    underlying: Pin<Box<dyn AsyncRead + Send + 'a>>, //the underlying Reader from which we get data
+    token: Vec<u8>,                                   //a full input tag loaded with a valid token
+    form_field: Vec<u8>,
+    scoped_token: Option<ScopedToken<'a>>, //give a token bound to a method and action, if enabled
+    form: Form,
     buf: Buffer,
     unparsed: Vec<u8>,
     state: ParseState, //state of the parser
@@ -82,241 +97,348 @@ pub struct CsrfProxy<'a> {
 }
 
 impl<'a> CsrfProxy<'a> {
-    pub fn from(underlying: Box<dyn Read + 'a>, token: &[u8]) -> Self {
-        let tag_begin = b"<input type=\"hidden\" name=\"csrf-token\" value=\"";
-        let tag_middle = token;
-        let tag_end = b"\"/>";
-        let mut token = Vec::new();
-        token.extend_from_slice(tag_begin);
-        token.extend_from_slice(tag_middle);
-        token.extend_from_slice(tag_end);
+    pub fn from(
+        underlying: Pin<Box<dyn AsyncRead + Send + 'a>>,
+        token: &[u8],
+        form_field: &str,
+    ) -> Self {
         CsrfProxy {
             underlying,
-            token,
+            token: input_tag(form_field.as_bytes(), token),
+            form_field: form_field.as_bytes().to_vec(),
+            scoped_token: None,
+            form: Form::default(),
             buf: Buffer::new(),
             unparsed: Vec::with_capacity(4096),
             state: ParseState::Init,
             eof: false,
         }
     }
-}
 
-impl<'a> Read for CsrfProxy<'a> {
-    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
-        while self.buf.len() < buf.len() && !(self.eof && self.unparsed.is_empty()) {
-            let len = if !self.eof || self.state == Init {
-                let unparsed_len = self.unparsed.len();
-                let mut new_unparsed_len = 4096;
-                while unparsed_len >= new_unparsed_len {
-                    new_unparsed_len += 4096;
-                }
-                self.unparsed.resize(new_unparsed_len, 0);
-                unparsed_len + match self.underlying.read(&mut self.unparsed[unparsed_len..]) {
-                    Ok(0) => {
-                        self.eof = true;
-                        0
-                    }
-                    Ok(len) => len,
-                    Err(e) => return Err(e),
-                }
-            } else {
-                let offset = self.buf.read(buf);
-                let unparsed_len = cmp::min(buf.len() - offset, self.unparsed.len());
-                buf[offset..offset + unparsed_len].copy_from_slice(&self.unparsed[0..unparsed_len]);
-                self.unparsed = self.unparsed[unparsed_len..].to_vec();
-                return Ok(unparsed_len + offset);
-            };
+    /// Insert tokens bound to the method and action of each form, given by `scoped_token` from
+    /// the method (the form's one, or its `_method` field) and the raw action. The unscoped token
+    /// is inserted if this give no token.
+    pub fn with_scoped_tokens<F>(mut self, scoped_token: F) -> Self
+    where
+        F: Fn(&str, &str) -> Option<Vec<u8>> + Send + 'a,
+    {
+        self.scoped_token = Some(Box::new(scoped_token));
+        self
+    }
 
-            self.unparsed.resize(len, 0); //we growed unparsed buffer to 4k before, so shrink it to it's needed size
-
-            let (consumed, insert_token) = {
-                let mut buf = &self.unparsed[..len]; //work only on the initialized part
-                let mut consumed = 0;
-                let mut leave = false;
-                let mut insert_token = false;
-                while !leave {
-                    self.state = match self.state {
-                        Init => {
-                            if let Some(tag_pos) = buf.iter().position(|&c| c as char == '<') {
-                                buf = &buf[tag_pos..];
-                                consumed += tag_pos;
-                                PartialFormMatch
+    fn form_token(&self) -> Vec<u8> {
+        //get the input tag to insert in the current form
+        let method = match &self.form.method {
+            Some(method) if !self.form.multipart => method,
+            _ => "post",
+        }; //like Rocket, only use "_method" in urlencoded forms
+        let action = self.form.action.as_deref().unwrap_or("");
+        self.scoped_token
+            .as_ref()
+            .and_then(|scoped_token| scoped_token(method, action))
+            .map(|token| input_tag(&self.form_field, &token))
+            .unwrap_or_else(|| self.token.clone())
+    }
+
+    fn parse(&mut self) {
+        //run the parser over unparsed data, moving everything it is done with to the output buffer
+        let (consumed, insert_token) = {
+            let mut buf = &self.unparsed[..];
+            let mut consumed = 0;
+            let mut leave = false;
+            let mut insert_token = false;
+            while !leave {
+                self.state = match self.state {
+                    Init => {
+                        if let Some(tag_pos) = buf.iter().position(|&c| c as char == '<') {
+                            buf = &buf[tag_pos..];
+                            consumed += tag_pos;
+                            PartialFormMatch
+                        } else {
+                            leave = true;
+                            consumed += buf.len();
+                            Init
+                        }
+                    }
+                    PartialFormMatch => {
+                        if let Some(lower_begin) =
+                            buf.get(1..5).map(|slice| slice.to_ascii_lowercase())
+                        {
+                            buf = &buf[5..];
+                            consumed += 5;
+                            if lower_begin == b"form" {
+                                FormTag
                             } else {
-                                leave = true;
-                                consumed += buf.len();
                                 Init
                             }
+                        } else {
+                            leave = true;
+                            PartialFormMatch
                         }
-                        PartialFormMatch => {
-                            if let Some(lower_begin) =
-                                buf.get(1..5).map(|slice| slice.to_ascii_lowercase())
-                            {
-                                buf = &buf[5..];
-                                consumed += 5;
-                                if lower_begin == b"form" {
-                                    SearchMethodForm
-                                } else {
-                                    Init
-                                }
+                    }
+                    FormTag => {
+                        if let Some(end) = tag_end(buf) {
+                            let attributes = parse_attributes(&buf[..end]);
+                            let attribute = |name: &str| {
+                                attributes.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v)
+                            };
+                            let post = attribute("method").is_some_and(|method| method.eq_ignore_ascii_case("post"));
+                            self.form = Form {
+                                action: attribute("action").cloned(),
+                                multipart: attribute("enctype")
+                                    .is_some_and(|enctype| enctype.eq_ignore_ascii_case("multipart/form-data")),
+                                method: None,
+                            };
+                            buf = &buf[end + 1..];
+                            consumed += end + 1;
+                            if post {
+                                SearchFormElem
                             } else {
-                                leave = true;
-                                PartialFormMatch
+                                Init
                             }
+                        } else {
+                            leave = true;
+                            FormTag
                         }
-                        SearchMethodForm => {
-                            if let Some(tag_pos) = buf.iter().position(|&c| c as char == ' ' || c as char == '>') {
-                                buf = &buf[tag_pos..];
-                                consumed += tag_pos;
-                                if buf[0] as char == ' ' {
-                                    MatchingMethodForm
-                                } else {
-                                    Init
-                                }
-                            } else {
-                                leave = true;
-                                consumed += buf.len();
-                                SearchMethodForm
-                            }
+                    }
+                    SearchFormElem => {
+                        if let Some(tag_pos) = buf.iter().position(|&c| c as char == '<') {
+                            buf = &buf[tag_pos..];
+                            consumed += tag_pos;
+                            PartialFormElemMatch
+                        } else {
+                            leave = true;
+                            consumed += buf.len();
+                            SearchFormElem
                         }
-                        MatchingMethodForm => {
-                            if let Some(lower_method) =
-                                buf.get(1..14).map(|slice| slice.to_ascii_lowercase())
+                    }
+                    PartialFormElemMatch => {
+                        if let Some(lower_begin) =
+                            buf.get(1..9).map(|slice| slice.to_ascii_lowercase())
+                        {
+                            if lower_begin.starts_with(b"/form")
+                                || lower_begin.starts_with(b"textarea")
+                                || lower_begin.starts_with(b"button")
+                                || lower_begin.starts_with(b"select")
                             {
-                                buf = &buf[1..];
-                                consumed+=1;
-                                if lower_method.starts_with(b"method=post") {
-                                    buf = &buf[11..];
-                                    consumed += 11;
-                                    SearchFormElem
-                                } else if lower_method.starts_with(b"method=\"post\"")
-                                    || lower_method.starts_with(b"method='post'")
-                                {
-                                    buf = &buf[13..];
-                                    consumed += 13;
-                                    SearchFormElem
-                                } else {
-                                    SearchMethodForm
-                                }
-                            } else {
+                                insert_token = true;
                                 leave = true;
-                                MatchingMethodForm
-                            }
-                        }
-                        SearchFormElem => {
-                            if let Some(tag_pos) = buf.iter().position(|&c| c as char == '<') {
-                                buf = &buf[tag_pos..];
-                                consumed += tag_pos;
-                                PartialFormElemMatch
+                                Init
+                            } else if lower_begin.starts_with(b"input") {
+                                SearchMethod(6)
                             } else {
-                                leave = true;
-                                consumed += buf.len();
+                                buf = &buf[9..];
+                                consumed += 9;
                                 SearchFormElem
                             }
+                        } else {
+                            leave = true;
+                            PartialFormElemMatch
                         }
-                        PartialFormElemMatch => {
-                            if let Some(lower_begin) =
-                                buf.get(1..9).map(|slice| slice.to_ascii_lowercase())
-                            {
-                                if lower_begin.starts_with(b"/form")
-                                    || lower_begin.starts_with(b"textarea")
-                                    || lower_begin.starts_with(b"button")
-                                    || lower_begin.starts_with(b"select")
-                                {
-                                    insert_token = true;
-                                    leave = true;
-                                    Init
-                                } else if lower_begin.starts_with(b"input") {
-                                    SearchMethod(6)
-                                } else {
-                                    buf = &buf[9..];
-                                    consumed += 9;
-                                    SearchFormElem
-                                }
-                            } else {
-                                leave = true;
-                                PartialFormElemMatch
-                            }
-                        }
-                        SearchMethod(pos) => {
-                            if let Some(meth_pos) = buf[pos..]
-                                .iter()
-                                .position(|&c| c as char == ' ' || c as char == '>')
-                            {
-                                if buf[meth_pos + pos] as char == ' ' {
-                                    PartialNameMatch(meth_pos + pos + 1)
-                                } else {
-                                    //reached '>'
-                                    insert_token = true;
-                                    leave = true;
-                                    Init
-                                }
+                    }
+                    SearchMethod(pos) => {
+                        if let Some(meth_pos) = buf[pos..]
+                            .iter()
+                            .position(|&c| c as char == ' ' || c as char == '>')
+                        {
+                            if buf[meth_pos + pos] as char == ' ' {
+                                PartialNameMatch(meth_pos + pos + 1)
                             } else {
+                                //reached '>'
+                                insert_token = true;
                                 leave = true;
-                                SearchMethod(buf.len())
+                                Init
                             }
+                        } else {
+                            leave = true;
+                            SearchMethod(buf.len())
                         }
-                        PartialNameMatch(pos) => {
-                            if let Some(lower_begin) = buf
-                                .get(pos..pos + 14)
-                                .map(|slice| slice.to_ascii_lowercase())
+                    }
+                    PartialNameMatch(pos) => {
+                        if let Some(lower_begin) = buf
+                            .get(pos..pos + 14)
+                            .map(|slice| slice.to_ascii_lowercase())
+                        {
+                            if lower_begin.starts_with(b"name=\"_method\"")
+                                || lower_begin.starts_with(b"name='_method'")
+                                || lower_begin.starts_with(b"name=_method")
                             {
-                                if lower_begin.starts_with(b"name=\"_method\"")
-                                    || lower_begin.starts_with(b"name='_method'")
-                                {
-                                    buf = &buf[pos + 14..];
-                                    consumed += pos + 14;
-                                    CloseInputTag
-                                } else if lower_begin.starts_with(b"name=_method") {
-                                    buf = &buf[pos + 12..];
-                                    consumed += pos + 12;
-                                    CloseInputTag
-                                } else {
-                                    SearchMethod(pos)
-                                }
+                                CloseInputTag
                             } else {
-                                leave = true;
-                                PartialNameMatch(pos)
+                                SearchMethod(pos)
                             }
-                        }
-                        CloseInputTag => {
+                        } else {
                             leave = true;
-                            if let Some(tag_pos) = buf.iter().position(|&c| c as char == '>') {
-                                buf = &buf[tag_pos + 1..];
-                                consumed += tag_pos + 1;
-                                insert_token = true;
-                                Init
-                            } else {
-                                consumed += buf.len();
-                                CloseInputTag
-                            }
+                            PartialNameMatch(pos)
+                        }
+                    }
+                    CloseInputTag => {
+                        leave = true;
+                        if let Some(end) = tag_end(buf) {
+                            self.form.method = parse_attributes(&buf[..end])
+                                .into_iter()
+                                .find(|(name, _)| name.eq_ignore_ascii_case("value"))
+                                .map(|(_, value)| value); //the method the form will be sent with
+                            consumed += end + 1;
+                            insert_token = true;
+                            Init
+                        } else {
+                            CloseInputTag
                         }
                     }
                 }
-                (consumed, insert_token)
-            };
+            }
+            (consumed, insert_token)
+        };
+        if consumed > 0 {
             self.buf.push_back(self.unparsed[0..consumed].to_vec());
-            if insert_token {
-                self.buf.push_back(self.token.clone());
+        } //never queue empty parts, an empty read would be seen as end of stream
+        if insert_token {
+            let token = self.form_token();
+            self.buf.push_back(token);
+        }
+        self.unparsed.drain(0..consumed);
+    }
+}
+
+fn input_tag(form_field: &[u8], token: &[u8]) -> Vec<u8> {
+    //make a full hidden input tag holding a token
+    let mut tag = Vec::new();
+    tag.extend_from_slice(b"<input type=\"hidden\" name=\"");
+    tag.extend_from_slice(form_field);
+    tag.extend_from_slice(b"\" value=\"");
+    tag.extend_from_slice(token);
+    tag.extend_from_slice(b"\"/>");
+    tag
+}
+
+fn tag_end(buf: &[u8]) -> Option<usize> {
+    //find the '>' closing a tag, ignoring those in quoted attribute values
+    let mut quote = None;
+    buf.iter().position(|&c| match quote {
+        Some(q) => {
+            if c == q {
+                quote = None;
             }
-            self.unparsed.drain(0..consumed);
+            false
+        }
+        None if c == b'"' || c == b'\'' => {
+            quote = Some(c);
+            false
+        }
+        None => c == b'>',
+    })
+}
+
+fn parse_attributes(tag: &[u8]) -> Vec<(String, String)> {
+    //get attributes of a tag, without its '<name' and '>', attributes without value are ignored
+    let mut attributes = Vec::new();
+    let mut rest = tag;
+    let is_space = |c: &u8| c.is_ascii_whitespace() || *c == b'/';
+    loop {
+        rest = &rest[rest.iter().position(|c| !is_space(c)).unwrap_or(rest.len())..];
+        if rest.is_empty() {
+            return attributes;
         }
-        Ok(self.buf.read(buf))
+        let name_len = rest
+            .iter()
+            .position(|&c| c == b'=' || is_space(&c))
+            .unwrap_or(rest.len());
+        let name = String::from_utf8_lossy(&rest[..name_len]).into_owned();
+        rest = &rest[name_len..];
+        if rest.first() != Some(&b'=') {
+            continue;
+        }
+        rest = &rest[1..];
+        let value = match rest.first() {
+            Some(&q) if q == b'"' || q == b'\'' => {
+                let len = rest[1..].iter().position(|&c| c == q).unwrap_or(rest.len() - 1);
+                let value = &rest[1..1 + len];
+                rest = &rest[cmp::min(len + 2, rest.len())..];
+                value
+            }
+            _ => {
+                let len = rest.iter().position(u8::is_ascii_whitespace).unwrap_or(rest.len());
+                let value = &rest[..len];
+                rest = &rest[len..];
+                value
+            }
+        };
+        attributes.push((name, String::from_utf8_lossy(value).into_owned()));
+    }
+}
+
+impl<'a> AsyncRead for CsrfProxy<'a> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        while this.buf.len() < buf.remaining() && !(this.eof && this.unparsed.is_empty()) {
+            if !this.eof || this.state == Init {
+                let unparsed_len = this.unparsed.len();
+                let mut new_unparsed_len = 4096;
+                while unparsed_len >= new_unparsed_len {
+                    new_unparsed_len += 4096;
+                }
+                this.unparsed.resize(new_unparsed_len, 0);
+                let mut read_buf = ReadBuf::new(&mut this.unparsed[unparsed_len..]);
+                let len = match this.underlying.as_mut().poll_read(cx, &mut read_buf) {
+                    Poll::Ready(Ok(())) if read_buf.filled().is_empty() => {
+                        this.eof = true;
+                        0
+                    }
+                    Poll::Ready(Ok(())) => read_buf.filled().len(),
+                    Poll::Ready(Err(e)) => {
+                        this.unparsed.truncate(unparsed_len);
+                        return Poll::Ready(Err(e));
+                    }
+                    Poll::Pending => {
+                        this.unparsed.truncate(unparsed_len);
+                        if this.buf.is_empty() {
+                            return Poll::Pending;
+                        }
+                        break; //send what is already parsed instead of waiting for more data
+                    }
+                };
+                this.unparsed.truncate(unparsed_len + len); //we growed unparsed buffer to 4k before, so shrink it to it's needed size
+            } else {
+                let offset = this.buf.read(buf.initialize_unfilled());
+                buf.advance(offset);
+                let unparsed_len = cmp::min(buf.remaining(), this.unparsed.len());
+                buf.put_slice(&this.unparsed[0..unparsed_len]);
+                this.unparsed.drain(0..unparsed_len);
+                return Poll::Ready(Ok(()));
+            };
+
+            this.parse();
+        }
+        let read = this.buf.read(buf.initialize_unfilled());
+        buf.advance(read);
+        Poll::Ready(Ok(()))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use csrf_proxy::{Buffer, CsrfProxy};
-    use std::io::{Cursor, Read};
+    use crate::csrf_proxy::{Buffer, CsrfProxy};
+    use std::io::Cursor;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
 
     macro_rules! must_finish {
         ($($func:expr);*) => {{
             use std::{sync::mpsc, thread, time::Duration};
             let (tx,rx) = mpsc::channel();
             thread::spawn(move || {
-                let _ = tx.send({
+                let runtime = tokio::runtime::Builder::new_current_thread()
+                    .build()
+                    .unwrap();
+                let _ = tx.send(runtime.block_on(async {
                     $($func);*
-                });
+                }));
             });
             rx.recv_timeout(Duration::from_secs(1))
                 .expect("expression did not finish exectution in time")
@@ -383,9 +505,9 @@ mod tests {
     Body of this simple doc
   </body>
 </html>";
-            let mut proxy = CsrfProxy::from(Box::new(Cursor::new(&data[..])), b"abcd");
+            let mut proxy = CsrfProxy::from(Box::pin(Cursor::new(&data[..])), b"abcd", "csrf-token");
             let mut pr_data = Vec::new();
-            let read = proxy.read_to_end(&mut pr_data);
+            let read = proxy.read_to_end(&mut pr_data).await;
             assert_eq!(read.unwrap(), data.len());
             assert_eq!(pr_data[..], data[..])
         }}
@@ -420,9 +542,9 @@ mod tests {
      <input type=\"hidden\" name=\"csrf-token\" value=\"abcd\"/></form>
   </body>
 </html>";
-            let mut proxy = CsrfProxy::from(Box::new(Cursor::new(&data[..])), b"abcd");
+            let mut proxy = CsrfProxy::from(Box::pin(Cursor::new(&data[..])), b"abcd", "csrf-token");
             let mut pr_data = Vec::new();
-            let read = proxy.read_to_end(&mut pr_data);
+            let read = proxy.read_to_end(&mut pr_data).await;
             assert_eq!(
                 read.unwrap(),
                 data.len() + "<input type=\"hidden\" name=\"csrf-token\" value=\"abcd\"/>".len()
@@ -456,9 +578,9 @@ mod tests {
      </form>
   </body>
 </html>";
-            let mut proxy = CsrfProxy::from(Box::new(Cursor::new(&data[..])), b"abcd");
+            let mut proxy = CsrfProxy::from(Box::pin(Cursor::new(&data[..])), b"abcd", "csrf-token");
             let mut pr_data = Vec::new();
-            let read = proxy.read_to_end(&mut pr_data);
+            let read = proxy.read_to_end(&mut pr_data).await;
             assert_eq!(
                 read.unwrap(),
                 data.len() + "<input type=\"hidden\" name=\"csrf-token\" value=\"abcd\"/>".len()
@@ -467,6 +589,22 @@ mod tests {
         }
     }}
 
+    #[test]
+    fn test_token_insertion_custom_field() {
+        must_finish!{{
+            let data = b"<form method=\"POST\"><input name=\"name\"/></form>";
+            let expected = b"<form method=\"POST\"><input type=\"hidden\" name=\"_csrf\" value=\"abcd\"/><input name=\"name\"/></form>";
+            let mut proxy = CsrfProxy::from(Box::pin(Cursor::new(&data[..])), b"abcd", "_csrf");
+            let mut pr_data = Vec::new();
+            let read = proxy.read_to_end(&mut pr_data).await;
+            assert_eq!(
+                read.unwrap(),
+                data.len() + "<input type=\"hidden\" name=\"_csrf\" value=\"abcd\"/>".len()
+            );
+            assert_eq!(pr_data[..], expected[..])
+        }}
+    }
+
     #[test]
     fn test_token_insertion_with_method() {
         must_finish!{{
@@ -492,9 +630,9 @@ mod tests {
      </form>
   </body>
 </html>";
-            let mut proxy = CsrfProxy::from(Box::new(Cursor::new(&data[..])), b"abcd");
+            let mut proxy = CsrfProxy::from(Box::pin(Cursor::new(&data[..])), b"abcd", "csrf-token");
             let mut pr_data = Vec::new();
-            let read = proxy.read_to_end(&mut pr_data);
+            let read = proxy.read_to_end(&mut pr_data).await;
             assert_eq!(
                 read.unwrap(),
                 data.len() + "<input type=\"hidden\" name=\"csrf-token\" value=\"abcd\"/>".len()
@@ -522,9 +660,9 @@ mod tests {
      </form>
   </body>
 </html>";
-            let mut proxy = CsrfProxy::from(Box::new(Cursor::new(&data[..])), b"abcd");
+            let mut proxy = CsrfProxy::from(Box::pin(Cursor::new(&data[..])), b"abcd", "csrf-token");
             let mut pr_data = Vec::new();
-            let read = proxy.read_to_end(&mut pr_data);
+            let read = proxy.read_to_end(&mut pr_data).await;
             assert_eq!(
                 read.unwrap(),
                 data.len() + "<input type=\"hidden\" name=\"csrf-token\" value=\"abcd\"/>".len()
@@ -533,11 +671,32 @@ mod tests {
         }}
     }
 
+    #[test]
+    fn test_scoped_token_insertion() {
+        must_finish!{{
+            let data = b"<form method=\"POST\" action=\"/a?b=>\"><input name=\"name\"/></form>\
+<form method=post action='/c' enctype=\"multipart/form-data\"><input name=_method value=put></form>\
+<form action=/d/e method=POST><input value=\"delete\" name=\"_method\"/></form>";
+            let expected = b"<form method=\"POST\" action=\"/a?b=>\"><input type=\"hidden\" name=\"csrf-token\" value=\"post /a?b=>\"/><input name=\"name\"/></form>\
+<form method=post action='/c' enctype=\"multipart/form-data\"><input name=_method value=put><input type=\"hidden\" name=\"csrf-token\" value=\"post /c\"/></form>\
+<form action=/d/e method=POST><input value=\"delete\" name=\"_method\"/><input type=\"hidden\" name=\"csrf-token\" value=\"delete /d/e\"/></form>";
+            let mut proxy = CsrfProxy::from(Box::pin(SlowReader::new(data)), b"abcd", "csrf-token")
+                .with_scoped_tokens(|method, action| Some(format!("{} {}", method, action).into_bytes()));
+            let mut pr_data = Vec::new();
+            proxy.read_to_end(&mut pr_data).await.unwrap();
+            assert_eq!(pr_data[..], expected[..])
+        }}
+    }
+
     struct ErrorReader {}
 
-    impl Read for ErrorReader {
-        fn read(&mut self, _buf: &mut [u8]) -> Result<usize, ::std::io::Error> {
-            Err(::std::io::Error::new(::std::io::ErrorKind::Other, ""))
+    impl AsyncRead for ErrorReader {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut ReadBuf<'_>,
+        ) -> Poll<Result<(), ::std::io::Error>> {
+            Poll::Ready(Err(::std::io::Error::other("")))
         }
     }
 
@@ -546,28 +705,46 @@ mod tests {
         must_finish!{{
             let buf = &mut [0; 1];
             let err = ErrorReader {};
-            let mut proxy_err = CsrfProxy::from(Box::new(err), &[0]);
-            let read = proxy_err.read(buf).unwrap_err();
+            let mut proxy_err = CsrfProxy::from(Box::pin(err), &[0], "csrf-token");
+            let read = proxy_err.read(buf).await.unwrap_err();
             assert_eq!(
                 read.kind(),
-                ::std::io::Error::new(::std::io::ErrorKind::Other, "").kind()
+                ::std::io::Error::other("").kind()
             );
         }}
     }
 
     struct SlowReader<'a> {
         content: &'a [u8],
+        ready: bool,
     }
 
-    impl<'a> Read for SlowReader<'a> {
-        fn read(&mut self, buf: &mut [u8]) -> Result<usize, ::std::io::Error> {
-            if self.content.len() > 0 {
-                buf[0] = self.content[0];
+    impl<'a> SlowReader<'a> {
+        fn new(content: &'a [u8]) -> Self {
+            SlowReader {
+                content,
+                ready: false,
+            }
+        }
+    }
+
+    impl<'a> AsyncRead for SlowReader<'a> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<Result<(), ::std::io::Error>> {
+            //alternate between pending and single bytes reads, like a slow network would
+            self.ready = !self.ready;
+            if !self.ready {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            if !self.content.is_empty() {
+                buf.put_slice(&self.content[..1]);
                 self.content = &self.content[1..];
-                Ok(1)
-            } else {
-                Ok(0)
             }
+            Poll::Ready(Ok(()))
         }
     }
 
@@ -601,9 +778,9 @@ mod tests {
      <input type=\"hidden\" name=\"csrf-token\" value=\"abcd\"/></form>
   </body>
 </html>";
-            let mut proxy = CsrfProxy::from(Box::new(SlowReader { content: data }), b"abcd");
+            let mut proxy = CsrfProxy::from(Box::pin(SlowReader::new(data)), b"abcd", "csrf-token");
             let mut pr_data = Vec::new();
-            let read = proxy.read_to_end(&mut pr_data);
+            let read = proxy.read_to_end(&mut pr_data).await;
             assert_eq!(
                 read.unwrap(),
                 data.len() + "<input type=\"hidden\" name=\"csrf-token\" value=\"abcd\"/>".len()
@@ -633,9 +810,9 @@ mod tests {
      </form>
   </body>
 </html>";
-            let mut proxy = CsrfProxy::from(Box::new(SlowReader { content: data }), b"abcd");
+            let mut proxy = CsrfProxy::from(Box::pin(SlowReader::new(data)), b"abcd", "csrf-token");
             let mut pr_data = Vec::new();
-            let read = proxy.read_to_end(&mut pr_data);
+            let read = proxy.read_to_end(&mut pr_data).await;
             assert_eq!(
                 read.unwrap(),
                 data.len() + "<input type=\"hidden\" name=\"csrf-token\" value=\"abcd\"/>".len()
@@ -665,9 +842,9 @@ mod tests {
      </form>
   </body>
 </html>";
-            let mut proxy = CsrfProxy::from(Box::new(SlowReader { content: data }), b"abcd");
+            let mut proxy = CsrfProxy::from(Box::pin(SlowReader::new(data)), b"abcd", "csrf-token");
             let mut pr_data = Vec::new();
-            let read = proxy.read_to_end(&mut pr_data);
+            let read = proxy.read_to_end(&mut pr_data).await;
             assert_eq!(
                 read.unwrap(),
                 data.len() + "<input type=\"hidden\" name=\"csrf-token\" value=\"abcd\"/>".len()
@@ -697,9 +874,9 @@ mod tests {
      </form>
   </body>
 </html>";
-            let mut proxy = CsrfProxy::from(Box::new(SlowReader { content: data }), b"abcd");
+            let mut proxy = CsrfProxy::from(Box::pin(SlowReader::new(data)), b"abcd", "csrf-token");
             let mut pr_data = Vec::new();
-            let read = proxy.read_to_end(&mut pr_data);
+            let read = proxy.read_to_end(&mut pr_data).await;
             assert_eq!(
                 read.unwrap(),
                 data.len() + "<input type=\"hidden\" name=\"csrf-token\" value=\"abcd\"/>".len()
@@ -722,9 +899,9 @@ mod tests {
           some text
         </p>";
 
-            let mut proxy = CsrfProxy::from(Box::new(Cursor::new(&data[..])), b"abcd");
+            let mut proxy = CsrfProxy::from(Box::pin(Cursor::new(&data[..])), b"abcd", "csrf-token");
             let mut pr_data = Vec::new();
-            let read = proxy.read_to_end(&mut pr_data);
+            let read = proxy.read_to_end(&mut pr_data).await;
             assert_eq!(read.unwrap(), data.len());
             assert_eq!(pr_data[..], data[..])
         }}
@@ -746,9 +923,9 @@ mod tests {
      </form>
   </body>
 </html>";
-            let mut proxy = CsrfProxy::from(Box::new(Cursor::new(&data[..])), b"abcd");
+            let mut proxy = CsrfProxy::from(Box::pin(Cursor::new(&data[..])), b"abcd", "csrf-token");
             let mut pr_data = Vec::new();
-            let read = proxy.read_to_end(&mut pr_data);
+            let read = proxy.read_to_end(&mut pr_data).await;
             assert_eq!(read.unwrap(), data.len());
             assert_eq!(pr_data[..], data[..])
         }}
@@ -758,9 +935,9 @@ mod tests {
     fn test_persian_content() {
         must_finish!({
             let data = std::fs::read_to_string("tests/persian-content.html").unwrap();
-            let mut proxy = CsrfProxy::from(Box::new(Cursor::new(&data[..])), b"abcd");
+            let mut proxy = CsrfProxy::from(Box::pin(Cursor::new(&data[..])), b"abcd", "csrf-token");
             let mut pr_data = String::new();
-            let read = proxy.read_to_string(&mut pr_data);
+            let read = proxy.read_to_string(&mut pr_data).await;
 
             let pr_len = read.unwrap() as i64;
             let data_len = data.len() as i64;
@@ -1,13 +1,14 @@
-use CSRF_COOKIE_NAME;
 use data_encoding::BASE64URL_NOPAD;
 use rocket::http::{Cookie, SameSite, Status};
 use rocket::outcome::Outcome;
 use rocket::request::{self, FromRequest};
-use rocket::{Request, State};
+use rocket::time::Duration;
+use rocket::Request;
 use serde::{Serialize, Serializer};
-use time::Duration;
 
-use crypto::CsrfProtection;
+use crate::crypto::TOKEN_PAIR_SIZE;
+use crate::csrf_fairing::{CsrfState, TokenMode};
+use crate::token_store::issue_token;
 
 /// Csrf token to insert into pages.
 ///
@@ -28,6 +29,10 @@ impl CsrfToken {
     }
 }
 
+/// Csrf cookie sent with the response, made by the first guard of a request so that every token
+/// of the request comes from the same secret.
+struct IssuedCookie(Option<String>);
+
 impl Serialize for CsrfToken {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -37,44 +42,94 @@ impl Serialize for CsrfToken {
     }
 }
 
-impl<'a, 'r> FromRequest<'a, 'r> for CsrfToken {
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for CsrfToken {
     type Error = ();
 
-    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
-        let (csrf_engine, duration) = request
-            .guard::<State<(CsrfProtection, u64)>>()
-            .unwrap()
-            .inner();
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        let state = match request.rocket().state::<CsrfState>() {
+            Some(state) => state,
+            None => return Outcome::Error((Status::InternalServerError, ())),
+        }; //the fairing was not attached to this Rocket instance
+        let csrf_engine = &state.engine;
+        let duration = state.duration;
+        let session = state.session_id(request);
+        if state.token_mode == TokenMode::Stateless {
+            return match session {
+                Some(session) => match csrf_engine.generate_signed_token(duration, &session, &[]) {
+                    Ok(token) => Outcome::Success(CsrfToken {
+                        value: BASE64URL_NOPAD.encode(&token),
+                    }),
+                    Err(_) => Outcome::Error((Status::InternalServerError, ())),
+                },
+                None => Outcome::Forward(Status::NotFound),
+            };
+        } //stateless tokens only depend on the session, there is no cookie to set
+        if state.token_mode == TokenMode::Stored {
+            return match (session, &state.token_store) {
+                (Some(session), Some(store)) => {
+                    let token = issue_token(store.as_ref(), csrf_engine, &session, duration, !state.one_time_tokens).await;
+                    match token.ok().and_then(|id| csrf_engine.mask_stored_id(&id).ok()) {
+                        Some(token) => Outcome::Success(CsrfToken {
+                            value: BASE64URL_NOPAD.encode(&token),
+                        }),
+                        None => Outcome::Error((Status::InternalServerError, ())),
+                    }
+                } //ids are masked, so that a reused id differs in each page
+                (None, _) => Outcome::Forward(Status::NotFound),
+                (Some(_), None) => Outcome::Error((Status::InternalServerError, ())),
+            };
+        } //stored tokens are kept server-side, there is no cookie to set either
+        let session = session.unwrap_or_default();
 
-        let mut cookies = request.cookies();
-        if cookies.iter().count() == 0
-            || cookies.iter().count() == 1 && cookies.get(CSRF_COOKIE_NAME).is_some()
-        {
-            Outcome::Forward(())
+        let cookies = request.cookies();
+        if cookies.iter().all(|cookie| {
+            cookie.name() == state.cookie_name
+                || Some(cookie.name()) == state.double_submit_cookie.as_deref()
+        }) {
+            //no cookies other than ours, there is no session to protect
+            Outcome::Forward(Status::NotFound)
         } else {
-            let mut token_value = cookies
-                .get(CSRF_COOKIE_NAME)
-                .and_then(|cookie| BASE64URL_NOPAD.decode(cookie.value().as_bytes()).ok());
-            let token_value = token_value.as_mut().and_then(|cookie| csrf_engine.parse_cookie(&mut *cookie).ok());
+            let issued = request.local_cache(|| {
+                let mut token_value = cookies
+                    .get(&state.cookie_name)
+                    .and_then(|cookie| BASE64URL_NOPAD.decode(cookie.value().as_bytes()).ok());
+                let token_value = token_value.as_mut().and_then(|cookie| csrf_engine.parse_cookie(&mut *cookie, &session).ok());
+                let mut buf = [0; TOKEN_PAIR_SIZE];
+                let pair = csrf_engine.generate_token_pair(token_value, duration, &session, &mut buf);
+                IssuedCookie(pair.ok().map(|(_, cookie)| BASE64URL_NOPAD.encode(cookie)))
+            }); //cookies added by this request are not visible through `get`, remember the first one
+            let mut token_value = match &issued.0 {
+                Some(cookie) => BASE64URL_NOPAD.decode(cookie.as_bytes()).unwrap_or_default(),
+                None => return Outcome::Error((Status::InternalServerError, ())),
+            };
+            let token_value = csrf_engine.parse_cookie(&mut token_value, &session).ok();
 
-            let mut buf = [0; 192];
-            match csrf_engine.generate_token_pair(token_value, *duration, &mut buf) {
+            let mut buf = [0; TOKEN_PAIR_SIZE];
+            let pair = if state.masked_tokens {
+                csrf_engine.generate_masked_token_pair(token_value, duration, &session, &mut buf)
+            } else {
+                csrf_engine
+                    .generate_token_pair(token_value, duration, &session, &mut buf)
+                    .map(|(token, cookie)| (token.to_vec(), cookie))
+            }; //either way, the token change at each request
+            match pair {
                 Ok((token, cookie)) => {
                     let c =
-                        Cookie::build(CSRF_COOKIE_NAME, BASE64URL_NOPAD.encode(cookie))
+                        Cookie::build((state.cookie_name.clone(), BASE64URL_NOPAD.encode(cookie)))
                             .http_only(true)
-                            .secure(true)
+                            .secure(state.secure_cookies)
                             .same_site(SameSite::Strict)
                             .path("/")
-                            .max_age(Duration::seconds(*duration as i64))
-                            .finish();
+                            .max_age(Duration::seconds(duration as i64))
+                            .build();
 
                     cookies.add(c);
                     Outcome::Success(CsrfToken {
-                        value: BASE64URL_NOPAD.encode(token),
+                        value: BASE64URL_NOPAD.encode(&token),
                     })
                 }
-                Err(_) => Outcome::Failure((Status::InternalServerError, ())),
+                Err(_) => Outcome::Error((Status::InternalServerError, ())),
             }
         }
     }
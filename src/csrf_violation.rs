@@ -0,0 +1,104 @@
+use rocket::http::Status;
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest};
+use rocket::Request;
+use std::fmt;
+
+use crate::csrf_guard::CsrfVerdict;
+
+/// Reason why a request was considered as a csrf violation.
+///
+/// The `CsrfViolation` type is the error returned by the [VerifiedCsrf](struct.VerifiedCsrf.html)
+/// request guard, telling which verification a request failed. It can also be obtained from the
+/// request in catchers and other fairings via [`of`], along with the uri it was sent to via
+/// [`uri_of`], or as a request guard in the handler of the default target, which it will forward
+/// from if the request was not a violation.
+///
+/// [`of`]: #method.of
+/// [`uri_of`]: #method.uri_of
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CsrfViolation {
+    /// The request had no csrf cookie.
+    MissingCookie,
+    /// The csrf cookie was not valid base64.
+    UndecodableCookie,
+    /// The csrf cookie could not be authenticated, it was modified, made with another key or in
+    /// another session.
+    InvalidCookie,
+    /// The csrf cookie expired.
+    ExpiredCookie,
+    /// The request had no token, neither in its body nor in a header.
+    MissingToken,
+    /// The token was not valid base64.
+    UndecodableToken,
+    /// The token could not be authenticated, it was modified, made with another key or in another
+    /// session.
+    InvalidToken,
+    /// The token and the csrf cookie were both valid, but don't belong to each other.
+    TokenMismatch,
+    /// The token was valid, but expired, even if the csrf cookie it belongs to was refreshed.
+    ExpiredToken,
+    /// The token was valid, but one-time tokens are enabled and it was already used.
+    ReusedToken,
+    /// Neither the `Origin` nor the `Referer` header matched an allowed origin.
+    InvalidOrigin,
+    /// Fetch metadata headers show a cross-site request, or a same-site one if those are
+    /// rejected too.
+    CrossSite,
+    /// The request was not verified, the fairing is probably not attached to Rocket.
+    NotVerified,
+}
+
+impl CsrfViolation {
+    /// Get the reason why a request violated csrf protection, if it did. This is mainly usefull
+    /// in catchers, when violations are rejected with a status.
+    pub fn of(request: &Request<'_>) -> Option<CsrfViolation> {
+        match request.local_cache(|| CsrfVerdict::Unverified) {
+            CsrfVerdict::Violation(violation) => Some(*violation),
+            _ => None,
+        }
+    }
+
+    /// Get the uri a request was sent to, if it violated csrf protection and was rerouted to an
+    /// exception, the default target, or the handler rejecting it with a status. This allows
+    /// catchers and targets to know which path was attacked.
+    pub fn uri_of<'r>(request: &'r Request<'_>) -> Option<&'r str> {
+        request.local_cache(|| ViolationUri(None)).0.as_deref()
+    }
+}
+
+/// Uri of a request before it was rerouted because of a violation.
+pub struct ViolationUri(pub Option<String>);
+
+impl fmt::Display for CsrfViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            CsrfViolation::MissingCookie => "missing csrf cookie",
+            CsrfViolation::UndecodableCookie => "undecodable csrf cookie",
+            CsrfViolation::InvalidCookie => "invalid csrf cookie",
+            CsrfViolation::ExpiredCookie => "expired csrf cookie",
+            CsrfViolation::MissingToken => "missing csrf token",
+            CsrfViolation::UndecodableToken => "undecodable csrf token",
+            CsrfViolation::InvalidToken => "invalid csrf token",
+            CsrfViolation::TokenMismatch => "csrf token does not match cookie",
+            CsrfViolation::ExpiredToken => "expired csrf token",
+            CsrfViolation::ReusedToken => "csrf token already used",
+            CsrfViolation::InvalidOrigin => "origin not allowed",
+            CsrfViolation::CrossSite => "cross-site request",
+            CsrfViolation::NotVerified => "request not verified",
+        };
+        f.write_str(reason)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for CsrfViolation {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        match CsrfViolation::of(request) {
+            Some(violation) => Outcome::Success(violation),
+            None => Outcome::Forward(Status::NotFound),
+        }
+    }
+}
@@ -1,6 +1,6 @@
 #![deny(missing_docs)]
-#![cfg_attr(feature = "cargo-clippy", deny(warnings))]
-#![feature(decl_macro, test, proc_macro_hygiene)]
+#![cfg_attr(clippy, deny(warnings))]
+#![cfg_attr(all(test, feature = "unstable"), feature(test))]
 //! # Rocket Csrf
 //!
 //! A crate to protect you application against csrf.
@@ -8,7 +8,15 @@
 //! ## Feature
 //!
 //! - Automatically protect all POST, PUT, DELETE and PATCH endpoints
-//! - Ability to define exceptions
+//! - Ability to define exceptions, and routes exempt from verification
+//! - Ability to protect only some routes, via the `VerifiedCsrf` request guard
+//! - Configuration from Rocket.toml, per profile, via the `csrf` table
+//! - Optional binding of tokens to your application's sessions
+//! - Optional binding of tokens inserted in forms to the form's action and method
+//! - Optional one-time tokens, with a pluggable store of used tokens
+//! - Cookieless tokens, signed along with your application's sessions
+//! - Server-side token stores, allowing to revoke and audit tokens of a session
+//! - Optional masked tokens, shorter than encrypted ones and still safe against BREACH
 //!
 //! ## Usage
 //!
@@ -27,15 +35,16 @@
 //! # extern crate rocket;
 //! # extern crate rocket_csrf;
 //! use rocket_csrf::CsrfFairingBuilder;
-//! # use rocket::Rocket;
 //!
-//! fn main() {
-//!     rocket::ignite()
+//! #[rocket::main]
+//! async fn main() {
+//!     let _ = rocket::build()
 //!         .attach(rocket_csrf::CsrfFairingBuilder::new()
 //!                 //configure it here
 //!                 .finalize().unwrap())
 //!         //add your routes, other fairings...
-//!         .launch();
+//!         .launch()
+//!         .await;
 //! }
 //! ```
 //!
@@ -43,40 +52,57 @@
 //! errors will simply be redirected to the route matching `/`
 //!
 extern crate data_encoding;
+extern crate regex;
 extern crate ring;
 extern crate serde;
+#[cfg(all(test, feature = "unstable"))]
 extern crate test;
-extern crate time;
+extern crate tokio;
 #[cfg(not(test))]
 extern crate rocket;
 #[cfg(test)]
 #[macro_use]
 extern crate rocket;
 
+mod csrf_config;
 mod csrf_fairing;
+mod csrf_guard;
 mod csrf_proxy;
 mod csrf_token;
+mod csrf_violation;
 mod path;
+mod path_set;
+mod replay_store;
+mod route_table;
+mod session_binder;
+mod token_store;
 mod utils;
 mod crypto;
 
-pub use self::csrf_fairing::{CsrfFairing, CsrfFairingBuilder};
+pub use self::csrf_config::{ConfigError, CsrfConfig, ExceptionConfig, TargetConfig};
+pub use self::csrf_fairing::{CheckMode, CsrfFairing, CsrfFairingBuilder, TokenMode};
+pub use self::csrf_guard::VerifiedCsrf;
 pub use self::csrf_token::CsrfToken;
+pub use self::csrf_violation::CsrfViolation;
+pub use self::path::{PathError, PathErrorKind};
+pub use self::replay_store::{MemoryReplayStore, ReplayStore};
+pub use self::session_binder::SessionBinder;
+pub use self::token_store::{FileTokenStore, MemoryTokenStore, TokenStore};
 const CSRF_COOKIE_NAME: &str = "csrf";
 const CSRF_FORM_FIELD: &str = "csrf-token";
-const CSRF_FORM_FIELD_MULTIPART: &[u8] = b"Content-Disposition: form-data; name=\"csrf-token\"";
+const CSRF_VIOLATION_PATH: &str = "/__rocket_csrf/violation";
 
 
-#[cfg(test)]
+#[cfg(all(test, feature = "unstable"))]
 mod tests {
     use super::*;
-    use rocket::{http::Cookie, local::Client};
+    use rocket::{http::Cookie, local::blocking::Client};
     use test::Bencher;
 
     #[bench]
     fn bench_plain_rocket(b: &mut Bencher) {
-        let rocket = ::rocket::ignite().mount("/", routes![index, no_modify]);
-        let client = Client::new(rocket).expect("valid rocket instance");
+        let rocket = ::rocket::build().mount("/", routes![index, no_modify]);
+        let client = Client::tracked(rocket).expect("valid rocket instance");
 
         b.iter(|| {
             for _ in 0..100 {
@@ -90,10 +116,10 @@ mod tests {
 
     #[bench]
     fn bench_modified_response(b: &mut Bencher) {
-        let rocket = ::rocket::ignite()
+        let rocket = ::rocket::build()
             .mount("/", routes![index, no_modify])
             .attach(CsrfFairingBuilder::new().finalize().unwrap());
-        let client = Client::new(rocket).expect("valid rocket instance");
+        let client = Client::tracked(rocket).expect("valid rocket instance");
 
         b.iter(|| {
             for _ in 0..100 {
@@ -107,10 +133,10 @@ mod tests {
 
     #[bench]
     fn bench_unmodified_response(b: &mut Bencher) {
-        let rocket = ::rocket::ignite()
+        let rocket = ::rocket::build()
             .mount("/", routes![index, no_modify])
             .attach(CsrfFairingBuilder::new().finalize().unwrap());
-        let client = Client::new(rocket).expect("valid rocket instance");
+        let client = Client::tracked(rocket).expect("valid rocket instance");
 
         b.iter(|| {
             for _ in 0..100 {
@@ -123,9 +149,8 @@ mod tests {
     }
 
     #[get("/")]
-    fn index() -> ::rocket::response::content::Content<&'static str> {
-        ::rocket::response::content::Content(
-            ::rocket::http::ContentType::HTML,
+    fn index() -> ::rocket::response::content::RawHtml<&'static str> {
+        ::rocket::response::content::RawHtml(
             "<!DOCTYPE html>
 <html>
     <head>
@@ -142,9 +167,8 @@ mod tests {
     }
 
     #[get("/no-modify")]
-    fn no_modify() -> ::rocket::response::content::Content<&'static str> {
-        ::rocket::response::content::Content(
-            ::rocket::http::ContentType::HTML,
+    fn no_modify() -> ::rocket::response::content::RawHtml<&'static str> {
+        ::rocket::response::content::RawHtml(
             "<!DOCTYPE html>
 <html>
     <head>
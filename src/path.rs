@@ -1,109 +1,115 @@
+use regex::Regex;
 use std::collections::HashMap;
-use utils::parse_args;
+use std::error::Error;
+use std::fmt;
+use crate::utils::{parse_args, parse_keyvalue};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Path {
     path: Vec<PathPart>,
     param: Option<HashMap<String, PathPart>>,
+    regex: Regex,
+    groups: Vec<String>,
+    prefix: bool,
 }
 
 impl Path {
-    pub fn from(path: &str) -> Self {
-        let (path, query) = if let Some(pos) = path.find('?') {
-            //cut the path at pos begining of query parameters
-            let (path, query) = path.split_at(pos);
-            let query = &query[1..];
-            (path, Some(query))
-        } else {
-            (path, None)
-        };
-        let path: Vec<_> = path
-                .split('/')//split path at each '/'
-                .filter(|seg| seg != &"")//remove empty segments
-                .map(|seg| {
-                    if seg.starts_with('<') && seg.ends_with("..>")  {
-                        PathPart::MultiDynamic(seg[1..seg.len() - 3].to_owned())
-                    } else if seg.starts_with('<') && seg.ends_with('>') {
-                        PathPart::Dynamic(seg[1..seg.len() - 1].to_owned())
-                    } else {//else it's static
-                        PathPart::Static(seg.to_owned())
+    pub fn parse(pattern: &str) -> Result<Self, PathError> {
+        Self::parse_with(pattern, false)
+    }
+
+    pub fn parse_prefix(pattern: &str) -> Result<Self, PathError> {
+        //parse a pattern matching paths starting with it
+        Self::parse_with(pattern, true)
+    }
+
+    fn parse_with(pattern: &str, prefix: bool) -> Result<Self, PathError> {
+        let (path, query) = split_query(pattern);
+        let mut parts = Vec::new();
+        let mut multidyn: Option<(usize, &str)> = None;
+        let mut position = 0;
+        for seg in path.split('/') {
+            //split path at each '/', keeping track of where each segment start
+            if !seg.is_empty() {
+                if let Some((position, seg)) = multidyn {
+                    return Err(PathError::new(PathErrorKind::MultiDynamicNotLast, position, seg));
+                } //a multi-segment part was followed by another segment
+                let part = PathPart::parse(seg).map_err(|kind| PathError::new(kind, position, seg))?;
+                if let PathPart::MultiDynamic(_) = part {
+                    multidyn = Some((position, seg));
+                }
+                parts.push(part);
+            }
+            position += seg.len() + 1;
+        }
+
+        let param = match query {
+            Some(query) => {
+                let mut param = HashMap::new();
+                for kv in query.split('&') {
+                    //do the same kind of parsing as above, but on query params
+                    if let Some((k, v)) = parse_keyvalue(kv) {
+                        let position = position + k.len() + 1;
+                        match PathPart::parse(v).map_err(|kind| PathError::new(kind, position, v))? {
+                            PathPart::MultiDynamic(_) => {
+                                return Err(PathError::new(PathErrorKind::MultiDynamicInQuery, position, v))
+                            }
+                            part @ PathPart::Static(_) | part @ PathPart::Dynamic(_) => param.insert(k.to_owned(), part),
+                            _ => return Err(PathError::new(PathErrorKind::PatternInQuery, position, v)),
+                        };
                     }
-                })
-                .collect();
-        let is_multidyn = |p: &PathPart| matches!(p, PathPart::MultiDynamic(_));
-        if !path.is_empty() && path[0..path.len() - 1].iter().any(is_multidyn) {
-            panic!("PathPart::MultiDynamic can only be found at end of path"); //TODO return error instead of panic
+                    position += kv.len() + 1;
+                }
+                Some(param)
+            }
+            None => None,
+        };
+
+        let mut groups = Vec::new();
+        let mut regex = String::from("^");
+        for part in &parts {
+            part.push_regex(&mut regex, &mut groups);
         }
+        regex.push_str(if prefix { "(?:/.*)?$" } else { "$" });
+        let regex = Regex::new(&regex).map_err(|_| PathError::new(PathErrorKind::InvalidRegex, 0, path))?;
+        Ok(Path { path: parts, param, regex, groups, prefix })
+    }
 
-        let param = query.map(|query| {
-            parse_args(query)
-                .map(|(k, v)| {
-                    (
-                        k.to_owned(),
-                        if v.starts_with('<') && v.ends_with("..>") {
-                            panic!("PathPart::MultiDynamic is invalid in query part");
-                        } else if v.starts_with('<') && v.ends_with('>') {
-                            //do the same kind of parsing as above, but on query params
-                            PathPart::Dynamic(v[1..v.len() - 1].to_owned())
-                        } else {
-                            PathPart::Static(v.to_owned())
-                        },
-                    )
-                })
-                .collect()
-        });
-        Path { path, param }
+    pub fn params(&self) -> impl Iterator<Item = &str> {
+        //list names of dynamic parts, in path and in query
+        self.path
+            .iter()
+            .chain(self.param.iter().flat_map(|param| param.values()))
+            .filter_map(PathPart::name)
+    }
+
+    pub fn has_patterns(&self) -> bool {
+        //whether this contains parts which match without capturing, so it can't be mapped
+        self.path.iter().any(|part| {
+            matches!(part, PathPart::Glob(_) | PathPart::AnySegments | PathPart::Optional(_))
+        })
+    }
+
+    pub fn regex(&self) -> &str {
+        self.regex.as_str()
     }
 
     pub fn extract(&self, uri: &str) -> Option<HashMap<&str, String>> {
         //try to match a str against a path, give back a hashmap of correponding parts if it matched
         let mut res: HashMap<&str, String> = HashMap::new();
-        let (path, query) = if let Some(pos) = uri.find('?') {
-            let (path, query) = uri.split_at(pos);
-            let query = &query[1..];
-            (path, Some(query))
-        } else {
-            (uri, None)
-        };
-        let mut path = path.split('/').filter(|seg| seg != &"");
-        let mut reference = self.path.iter();
-        loop {
-            match reference.next() {
-                Some(reference) => {
-                    match reference {
-                        PathPart::Static(reference) => {
-                            //static, but not the same, fail to parse
-                            if let Some(val) = path.next() {
-                                if val != reference {
-                                    return None;
-                                }
-                            } else {
-                                return None;
-                            }
-                        }
-                        PathPart::Dynamic(key) => {
-                            //dynamic, store to hashmap
-                            if let Some(val) = path.next() {
-                                res.insert(key, val.to_owned());
-                            } else {
-                                return None;
-                            }
-                        }
-                        PathPart::MultiDynamic(key) => {
-                            let val = path.collect::<Vec<_>>().join("/");
-                            res.insert(key, val);
-                            break;
-                        }
-                    };
-                }
-                None => if path.next().is_some() {
-                    //not the same lenght, fail to parse
-                    return None;
-                } else {
-                    break;
-                },
+        let (path, query) = split_query(uri);
+        let path = normalize(path);
+        let captures = self.regex.captures(&path)?;
+        for (i, name) in self.groups.iter().enumerate() {
+            if let Some(value) = captures.name(&format!("csrf_{}", i)) {
+                res.insert(name, value.as_str().to_owned());
             }
         }
+        for part in &self.path {
+            if let PathPart::MultiDynamic(name) = part {
+                res.entry(name).or_default();
+            }
+        } //multi-segment parts match empty paths
         if let Some(query) = query {
             if let Some(ref param) = self.param {
                 let hm = parse_args(query).collect::<HashMap<&str, &str>>();
@@ -117,12 +123,10 @@ impl Path {
                             //dynamic, store to hashmap
                             res.insert(key, hm.get::<&str>(&(k as &str))?.to_string());
                         }
-                        PathPart::MultiDynamic(_) => {
-                            unreachable!("Paramater part can't contain MultiDynamic");
-                        }
+                        _ => unreachable!("Paramater part can only contain Static and Dynamic"),
                     }
                 }
-            } else {
+            } else if !self.prefix {
                 //param in query, but not in reference, fail to parse
                 return None;
             }
@@ -141,9 +145,10 @@ impl Path {
             res.push('/');
             match seg {
                 PathPart::Static(val) => res.push_str(val),
-                PathPart::Dynamic(val) | PathPart::MultiDynamic(val) => {
+                PathPart::Dynamic(val) | PathPart::MultiDynamic(val) | PathPart::Regex(val, _) => {
                     res.push_str(param.get::<str>(val)?)
                 }
+                PathPart::Glob(_) | PathPart::AnySegments | PathPart::Optional(_) => return None,
             }
         }
         if res.is_empty() {
@@ -158,9 +163,7 @@ impl Path {
                 match v {
                     PathPart::Static(val) => res.push_str(val),
                     PathPart::Dynamic(val) => res.push_str(param.get::<str>(val)?),
-                    PathPart::MultiDynamic(_) => {
-                        unreachable!("Paramater part can't contain MultiDynamic");
-                    }
+                    _ => unreachable!("Paramater part can only contain Static and Dynamic"),
                 }
                 res.push('&');
             }
@@ -169,20 +172,188 @@ impl Path {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 enum PathPart {
     Static(String),
     Dynamic(String),
     MultiDynamic(String),
+    Regex(String, String),
+    Glob(String),
+    AnySegments,
+    Optional(Box<PathPart>),
+}
+
+impl PathPart {
+    fn parse(seg: &str) -> Result<Self, PathErrorKind> {
+        if seg.starts_with('[') && seg.ends_with(']') && seg.len() >= 2 {
+            return match PathPart::parse(&seg[1..seg.len() - 1])? {
+                PathPart::MultiDynamic(_) | PathPart::AnySegments | PathPart::Optional(_) => Err(PathErrorKind::Malformed),
+                PathPart::Static(ref val) if val.is_empty() => Err(PathErrorKind::Malformed),
+                part => Ok(PathPart::Optional(Box::new(part))),
+            };
+        } //an optional segment may be any single segment
+        if seg == "**" {
+            return Ok(PathPart::AnySegments);
+        }
+        let (part, name) = if seg.starts_with('<') && seg.ends_with("..>") && seg.len() >= 4 {
+            (PathPart::MultiDynamic(seg[1..seg.len() - 3].to_owned()), &seg[1..seg.len() - 3])
+        } else if seg.starts_with('<') && seg.ends_with('>') && seg.len() >= 2 {
+            let inner = &seg[1..seg.len() - 1];
+            if let Some(pos) = inner.find(':') {
+                let (name, regex) = (&inner[..pos], &inner[pos + 1..]);
+                if regex.is_empty() || Regex::new(regex).is_err() {
+                    return Err(PathErrorKind::InvalidRegex);
+                }
+                (PathPart::Regex(name.to_owned(), regex.to_owned()), name)
+            } else {
+                (PathPart::Dynamic(inner.to_owned()), inner)
+            }
+        } else if seg.contains('*') {
+            (PathPart::Glob(seg.to_owned()), seg)
+        } else {
+            //else it's static
+            (PathPart::Static(seg.to_owned()), seg)
+        };
+        if name.contains(['<', '>']) {
+            Err(PathErrorKind::Malformed)
+        } else if name.is_empty() && !matches!(part, PathPart::Static(_)) {
+            Err(PathErrorKind::EmptyName)
+        } else {
+            Ok(part)
+        }
+    }
+
+    fn name(&self) -> Option<&str> {
+        match self {
+            PathPart::Dynamic(name) | PathPart::MultiDynamic(name) | PathPart::Regex(name, _) => Some(name),
+            PathPart::Optional(part) => part.name(),
+            PathPart::Static(_) | PathPart::Glob(_) | PathPart::AnySegments => None,
+        }
+    }
+
+    fn push_regex(&self, regex: &mut String, groups: &mut Vec<String>) {
+        //translate this part into a regex matching it, including the '/' before it
+        let mut group = |regex: &mut String, name: &str| {
+            regex.push_str(&format!("(?P<csrf_{}>", groups.len()));
+            groups.push(name.to_owned());
+        }; //dynamic parts are captured by named groups, so groups of user regexes don't matter
+        match self {
+            PathPart::Static(val) => {
+                regex.push('/');
+                regex.push_str(&regex::escape(val));
+            }
+            PathPart::Dynamic(name) => {
+                regex.push('/');
+                group(regex, name);
+                regex.push_str("[^/]+)");
+            }
+            PathPart::MultiDynamic(name) => {
+                regex.push_str("(?:/");
+                group(regex, name);
+                regex.push_str(".*))?");
+            }
+            PathPart::Regex(name, re) => {
+                regex.push('/');
+                group(regex, name);
+                regex.push_str("(?:");
+                regex.push_str(re);
+                regex.push_str("))");
+            }
+            PathPart::Glob(glob) => {
+                regex.push('/');
+                let glob = glob.split('*').map(regex::escape).collect::<Vec<_>>();
+                regex.push_str(&glob.join("[^/]*"));
+            }
+            PathPart::AnySegments => regex.push_str("(?:/[^/]+)*"),
+            PathPart::Optional(part) => {
+                regex.push_str("(?:");
+                part.push_regex(regex, groups);
+                regex.push_str(")?");
+            }
+        }
+    }
+}
+
+fn split_query(uri: &str) -> (&str, Option<&str>) {
+    //cut an uri at the begining of query parameters
+    match uri.find('?') {
+        Some(pos) => (&uri[..pos], Some(&uri[pos + 1..])),
+        None => (uri, None),
+    }
+}
+
+pub fn normalize(path: &str) -> String {
+    //remove empty segments, and the trailing '/', so paths can be matched against regexes
+    path.split('/').filter(|seg| !seg.is_empty()).flat_map(|seg| vec!["/", seg]).collect()
+}
+
+/// Error returned when a path pattern can't be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathError {
+    /// What is wrong with the pattern.
+    pub kind: PathErrorKind,
+    /// Position, in bytes, of the offending segment in the pattern.
+    pub position: usize,
+    /// The offending segment.
+    pub segment: String,
+}
+
+impl PathError {
+    fn new(kind: PathErrorKind, position: usize, segment: &str) -> Self {
+        PathError {
+            kind,
+            position,
+            segment: segment.to_owned(),
+        }
+    }
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at position {}: `{}`", self.kind, self.position, self.segment)
+    }
+}
+
+impl Error for PathError {}
+
+/// Reasons for which a path pattern can be invalid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathErrorKind {
+    /// A `<name..>` part is followed by other segments.
+    MultiDynamicNotLast,
+    /// A `<name..>` part is used as a query parameter.
+    MultiDynamicInQuery,
+    /// A `<>` or `<..>` part has no name.
+    EmptyName,
+    /// A segment contains a `<` or `>` which doesn't delimit a dynamic part, or an optional
+    /// segment doesn't contain a single segment.
+    Malformed,
+    /// A `<name:regex>` part contains an invalid regex.
+    InvalidRegex,
+    /// A glob, regex or optional part is used as a query parameter.
+    PatternInQuery,
+}
+
+impl fmt::Display for PathErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PathErrorKind::MultiDynamicNotLast => "multi-segment part before end of path",
+            PathErrorKind::MultiDynamicInQuery => "multi-segment part in query",
+            PathErrorKind::EmptyName => "dynamic part without name",
+            PathErrorKind::Malformed => "malformed segment",
+            PathErrorKind::InvalidRegex => "invalid regex",
+            PathErrorKind::PatternInQuery => "pattern in query",
+        })
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use path::Path;
+    use crate::path::{Path, PathErrorKind};
     use std::collections::HashMap;
     #[test]
     fn test_static_path_without_query() {
-        let no_query = Path::from("/path/no_query");
+        let no_query = Path::parse("/path/no_query").unwrap();
         assert!(no_query.extract("/path/something").is_none());
         assert!(no_query.extract("/path").is_none());
         assert!(no_query.extract("/path/no_query/longer").is_none());
@@ -200,7 +371,7 @@ mod tests {
 
     #[test]
     fn test_static_path_with_query() {
-        let query = Path::from("/path/query?param=value&param2=value2");
+        let query = Path::parse("/path/query?param=value&param2=value2").unwrap();
         assert!(query.extract("/path/query").is_none());
         assert!(
             query
@@ -241,7 +412,7 @@ mod tests {
 
     #[test]
     fn test_dynamic_path_without_query() {
-        let no_query = Path::from("/path/<with>/<dynamic>/values");
+        let no_query = Path::parse("/path/<with>/<dynamic>/values").unwrap();
         assert!(
             no_query
                 .extract("/path/with/dynamic/values/longer")
@@ -254,7 +425,7 @@ mod tests {
                 .extract("/path/with/dynamic/values?and=query")
                 .is_none()
         );
-        let end_dyn = Path::from("/path/<with>/<dynamic>");
+        let end_dyn = Path::parse("/path/<with>/<dynamic>").unwrap();
         assert!(end_dyn.extract("/path/shorter").is_none());
 
         let hashmap = no_query.extract("/path/containing/moving/values").unwrap();
@@ -280,7 +451,7 @@ mod tests {
 
     #[test]
     fn test_dynamic_path_with_query() {
-        let query = Path::from("/path/<with>/<dynamic>/values?key=<value>&static=static");
+        let query = Path::parse("/path/<with>/<dynamic>/values?key=<value>&static=static").unwrap();
         assert!(
             query
                 .extract("/path/with/dynamic/values?key=something&static=error")
@@ -325,20 +496,48 @@ mod tests {
     }
 
     #[test]
-    #[should_panic(expected = "PathPart::MultiDynamic is invalid in query part")]
     fn test_mutlidynamic_in_query() {
-        Path::from("/path?query=<dynamic..>");
+        let err = Path::parse("/path?other=value&query=<dynamic..>").unwrap_err();
+        assert_eq!(err.kind, PathErrorKind::MultiDynamicInQuery);
+        assert_eq!(err.position, 24);
+        assert_eq!(err.segment, "<dynamic..>");
     }
 
     #[test]
-    #[should_panic(expected = "PathPart::MultiDynamic can only be found at end of path")]
     fn test_multidynamic_before_end_of_path() {
-        Path::from("/<dynamic..>/something");
+        let err = Path::parse("/path/<dynamic..>/something").unwrap_err();
+        assert_eq!(err.kind, PathErrorKind::MultiDynamicNotLast);
+        assert_eq!(err.position, 6);
+        assert_eq!(err.segment, "<dynamic..>");
+    }
+
+    #[test]
+    fn test_malformed_dynamic() {
+        let err = Path::parse("/path/<typo/something").unwrap_err();
+        assert_eq!(err.kind, PathErrorKind::Malformed);
+        assert_eq!(err.position, 6);
+        assert_eq!(err.segment, "<typo");
+
+        let err = Path::parse("/path?key=<>").unwrap_err();
+        assert_eq!(err.kind, PathErrorKind::EmptyName);
+        assert_eq!(err.position, 10);
+        assert_eq!(
+            err.to_string(),
+            "dynamic part without name at position 10: `<>`"
+        );
+    }
+
+    #[test]
+    fn test_params() {
+        let path = Path::parse("/path/<with>/<multidyn..>?key=<value>&static=static").unwrap();
+        let mut params = path.params().collect::<Vec<_>>();
+        params.sort_unstable();
+        assert_eq!(params, vec!["multidyn", "value", "with"]);
     }
 
     #[test]
     fn test_multidynamic() {
-        let query = Path::from("/path/<multidyn..>?static=static");
+        let query = Path::parse("/path/<multidyn..>?static=static").unwrap();
 
         let hashmap = query.extract("/path?static=static").unwrap();
         assert_eq!(hashmap.len(), 1);
@@ -360,10 +559,10 @@ mod tests {
 
     #[test]
     fn test_empty_url() {
-        let query = Path::from("/");
+        let query = Path::parse("/").unwrap();
         assert_eq!(query.map(&HashMap::new()).unwrap(), "/");
 
-        let query = Path::from("/?param=<value>");
+        let query = Path::parse("/?param=<value>").unwrap();
 
         let hashmap = query.extract("/?param=something").unwrap();
         assert_eq!(hashmap.len(), 1);
@@ -0,0 +1,104 @@
+use regex::RegexSet;
+use std::collections::HashMap;
+
+use crate::path::{normalize, Path};
+
+/// Set of paths with associated values, precompiled so that an uri is matched against all of
+/// them at once.
+pub struct PathSet<T> {
+    paths: Vec<(Path, T)>,
+    set: RegexSet,
+}
+
+impl<T> PathSet<T> {
+    pub fn new(paths: Vec<(Path, T)>) -> Result<Self, regex::Error> {
+        let set = RegexSet::new(paths.iter().map(|(path, _)| path.regex()))?;
+        Ok(PathSet { paths, set })
+    }
+
+    /// Get values of paths matching an uri, in the order they were given, with the dynamic parts
+    /// they captured.
+    pub fn matches<'a>(&'a self, uri: &'a str) -> impl Iterator<Item = (&'a T, HashMap<&'a str, String>)> + 'a {
+        let path = uri.split('?').next().unwrap_or("");
+        self.set
+            .matches(&normalize(path))
+            .into_iter()
+            .filter_map(move |i| {
+                let (path, value) = &self.paths[i];
+                path.extract(uri).map(|param| (value, param))
+            }) //the set only match paths, queries are verified for candidates
+    }
+
+    pub fn is_match(&self, uri: &str) -> bool {
+        self.matches(uri).next().is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::path::Path;
+    use crate::path_set::PathSet;
+
+    fn set(patterns: &[&str]) -> PathSet<usize> {
+        PathSet::new(
+            patterns
+                .iter()
+                .enumerate()
+                .map(|(i, pattern)| (Path::parse(pattern).unwrap(), i))
+                .collect(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_globs() {
+        let set = set(&["/api/**/upload", "/static/*.css", "/*/index"]);
+        let matching = |uri| set.matches(uri).map(|(&i, _)| i).collect::<Vec<_>>();
+        assert_eq!(matching("/api/upload"), vec![0]);
+        assert_eq!(matching("/api/v1/files/upload"), vec![0]);
+        assert_eq!(matching("/api/v1/files/upload/more"), Vec::<usize>::new());
+        assert_eq!(matching("/static/main.css"), vec![1]);
+        assert_eq!(matching("/static/main.css?v=2"), Vec::<usize>::new());
+        assert_eq!(matching("/static/css/main.css"), Vec::<usize>::new());
+        assert_eq!(matching("/static/index"), vec![2]);
+        assert!(!set.is_match("/index"));
+    }
+
+    #[test]
+    fn test_optional_and_regex() {
+        let set = set(&["/api/[v1]/user/<id:[0-9]+>", "/api/[<version>]/user/<name>?x=<x>"]);
+
+        let mut matches = set.matches("/api/user/42");
+        let (&i, param) = matches.next().unwrap();
+        assert_eq!(i, 0);
+        assert_eq!(param.get("id").unwrap(), "42");
+        assert!(matches.next().is_none()); //second pattern require a query
+
+        let (&i, param) = set.matches("/api/v2/user/bob?x=y").next().unwrap();
+        assert_eq!(i, 1);
+        assert_eq!(param.get("version").unwrap(), "v2");
+        assert_eq!(param.get("name").unwrap(), "bob");
+        assert_eq!(param.get("x").unwrap(), "y");
+
+        let (&i, param) = set.matches("/api/user/bob?x=y").next().unwrap();
+        assert_eq!(i, 1);
+        assert!(!param.contains_key("version"));
+
+        assert!(!set.is_match("/api/v1/user/4a"));
+    }
+
+    #[test]
+    fn test_prefixes() {
+        let set = PathSet::new(vec![
+            (Path::parse_prefix("/static").unwrap(), ()),
+            (Path::parse_prefix("/api/*/docs").unwrap(), ()),
+        ])
+        .unwrap();
+        assert!(set.is_match("/static"));
+        assert!(set.is_match("/static/css/main.css?with=query"));
+        assert!(!set.is_match("/staticfile"));
+        assert!(!set.is_match("/other?/static"));
+        assert!(set.is_match("/api/v1/docs/index.html"));
+        assert!(!set.is_match("/api/docs"));
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Store of the tokens which were already used.
+///
+/// The `ReplayStore` trait is used when one-time tokens are enabled, to remember which tokens
+/// were consumed until they expire, so they can't be used again. The default implementation,
+/// [MemoryReplayStore](struct.MemoryReplayStore.html), keeps them in memory, which isn't shared
+/// between instances of an application, so you may implement this on top of a shared store, like
+/// Redis, instead.
+///
+/// # Example
+///
+///  ```rust,no_run
+/// # extern crate rocket;
+/// # extern crate rocket_csrf;
+/// use rocket_csrf::{CsrfFairingBuilder, ReplayStore};
+///
+/// struct SharedStore;
+///
+/// #[rocket::async_trait]
+/// impl ReplayStore for SharedStore {
+///     async fn consume(&self, id: &[u8], ttl: u64) -> bool {
+///         //atomically insert id with a timeout of ttl seconds, if it was not already present
+///         true
+///     }
+/// }
+///
+/// #[rocket::main]
+/// async fn main() {
+///     let _ = rocket::build()
+///         .attach(rocket_csrf::CsrfFairingBuilder::new()
+///                 .set_replay_store(SharedStore)
+///                 .finalize().unwrap())
+///         //add your routes, other fairings...
+///         .launch()
+///         .await;
+/// }
+/// ```
+#[rocket::async_trait]
+pub trait ReplayStore: Send + Sync + 'static {
+    /// Mark a token, given by its unique id, as used for the next `ttl` seconds. Return false if
+    /// it was already used, or if this can't be known, in which case the request is rejected.
+    async fn consume(&self, id: &[u8], ttl: u64) -> bool;
+}
+
+/// In memory [ReplayStore](trait.ReplayStore.html), remembering at most a given number of
+/// tokens until they expire.
+///
+/// Used tokens are never forgotten before they expire, so once the store is full, tokens can't be
+/// consumed and requests using them are rejected until some expire. The capacity should then be
+/// greater than the number of forms sent during the lifetime of a token. Default capacity is
+/// 65536 tokens.
+pub struct MemoryReplayStore {
+    capacity: usize,
+    expires: Mutex<HashMap<Vec<u8>, u64>>,
+}
+
+impl MemoryReplayStore {
+    /// Create a store remembering at most `capacity` tokens.
+    pub fn new(capacity: usize) -> Self {
+        MemoryReplayStore {
+            capacity,
+            expires: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Default for MemoryReplayStore {
+    fn default() -> Self {
+        Self::new(65536)
+    }
+}
+
+#[rocket::async_trait]
+impl ReplayStore for MemoryReplayStore {
+    async fn consume(&self, id: &[u8], ttl: u64) -> bool {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let mut expires = match self.expires.lock() {
+            Ok(expires) => expires,
+            Err(_) => return false,
+        };
+        if expires.get(id).is_some_and(|&expire| expire > now) {
+            return false;
+        }
+        if !expires.contains_key(id) && expires.len() >= self.capacity {
+            expires.retain(|_, expire| *expire > now);
+            if expires.len() >= self.capacity {
+                eprintln!("[rocket_csrf] Replay store is full, rejecting one-time token");
+                return false;
+            } //forgetting unexpired tokens would allow to use them again
+        } //only forget expired tokens when the store is full
+        expires.insert(id.to_vec(), now + ttl);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::replay_store::{MemoryReplayStore, ReplayStore};
+
+    #[test]
+    fn test_memory_store() {
+        rocket::async_test(async {
+            let store = MemoryReplayStore::new(2);
+            assert!(store.consume(b"a", 60).await);
+            assert!(!store.consume(b"a", 60).await);
+            assert!(store.consume(b"b", 60).await);
+            assert!(!store.consume(b"c", 60).await); //the store is full
+            assert!(!store.consume(b"a", 60).await); //"a" is not forgotten
+
+            let store = MemoryReplayStore::new(1);
+            assert!(store.consume(b"d", 0).await);
+            assert!(store.consume(b"d", 0).await); //expired tokens are forgotten
+            assert!(store.consume(b"e", 60).await);
+            assert!(!store.consume(b"e", 60).await);
+        })
+    }
+}
@@ -0,0 +1,190 @@
+use rocket::http::{MediaType, Method};
+use rocket::{Request, Route};
+use std::fmt;
+
+use crate::path::Path;
+
+/// Reference to a route, which is resolved against mounted routes when Rocket ignite.
+#[derive(Debug, Clone)]
+pub enum RouteRef {
+    Name(String),
+    Route {
+        name: Option<String>,
+        method: Method,
+        uri: String,
+    },
+}
+
+impl RouteRef {
+    pub fn resolves_to(&self, route: &Route) -> bool {
+        match self {
+            RouteRef::Name(name) => route.name.as_deref() == Some(name),
+            RouteRef::Route { name, method, uri } => {
+                route.name.as_deref() == name.as_deref()
+                    && route.method == *method
+                    && route.uri.unmounted_origin.to_string() == *uri
+            } //a route given by routes![] is unmounted, it match all places where it was mounted
+        }
+    }
+}
+
+impl From<&Route> for RouteRef {
+    fn from(route: &Route) -> Self {
+        RouteRef::Route {
+            name: route.name.as_ref().map(|name| name.to_string()),
+            method: route.method,
+            uri: route.uri.unmounted_origin.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for RouteRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RouteRef::Name(name) => f.write_str(name),
+            RouteRef::Route { method, uri, .. } => write!(f, "{} {}", method, uri),
+        }
+    }
+}
+
+/// Mounted routes to which exceptions or exemptions apply, matched against requests before
+/// routing. This is a simplified copy of Rocket's matching: the first route by rank whose method,
+/// path, static query and format match is taken, as whether it would forward is unknown.
+pub struct RouteTable {
+    routes: Vec<MountedRoute>,
+}
+
+struct MountedRoute {
+    method: Method,
+    rank: isize,
+    format: Option<MediaType>,
+    path: Vec<Segment>,
+    trailing: bool,
+    query: Vec<(String, String)>,
+    exception: Option<(Path, Path, Option<Method>)>,
+    exempt: bool,
+}
+
+enum Segment {
+    Static(String),
+    Dynamic,
+}
+
+impl RouteTable {
+    pub fn new() -> Self {
+        RouteTable { routes: Vec::new() }
+    }
+
+    /// Add a mounted route, with the exception to apply to requests Rocket would route to it, if
+    /// any, and whether it is exempt from verification. Other routes are needed too, as they may
+    /// take precedence over those.
+    pub fn add(&mut self, route: &Route, exception: Option<(Path, Path, Option<Method>)>, exempt: bool) {
+        let path = route
+            .uri
+            .origin
+            .path()
+            .segments()
+            .map(|seg| {
+                if seg.starts_with('<') && seg.ends_with('>') {
+                    Segment::Dynamic
+                } else {
+                    Segment::Static(seg.to_owned())
+                }
+            })
+            .collect();
+        let trailing = route
+            .uri
+            .origin
+            .path()
+            .segments()
+            .last()
+            .is_some_and(|seg| seg.starts_with('<') && seg.ends_with("..>"));
+        let query = route
+            .uri
+            .origin
+            .query()
+            .map(|query| {
+                query
+                    .segments()
+                    .filter(|(key, _)| !key.starts_with('<'))
+                    .map(|(key, value)| (key.to_owned(), value.to_owned()))
+                    .collect()
+            })
+            .unwrap_or_default(); //only static fields are relevant to matching
+        self.routes.push(MountedRoute {
+            method: route.method,
+            rank: route.rank,
+            format: route.format.clone(),
+            path,
+            trailing,
+            query,
+            exception,
+            exempt,
+        });
+        self.routes.sort_by_key(|route| route.rank); //routes are attempted in ascending rank order
+    }
+
+    /// Get the exception of the route this request would be routed to, if any.
+    pub fn exception(&self, request: &Request<'_>) -> Option<&(Path, Path, Option<Method>)> {
+        self.find(request).and_then(|route| route.exception.as_ref())
+    }
+
+    /// Get whether the route this request would be routed to is exempt from verification.
+    pub fn is_exempt(&self, request: &Request<'_>) -> bool {
+        self.find(request).is_some_and(|route| route.exempt)
+    }
+
+    fn find(&self, request: &Request<'_>) -> Option<&MountedRoute> {
+        self.routes.iter().find(|route| route.matches(request))
+    }
+}
+
+impl MountedRoute {
+    fn matches(&self, request: &Request<'_>) -> bool {
+        self.method == request.method()
+            && self.path_matches(request)
+            && self.query_matches(request)
+            && self.format_matches(request)
+    }
+
+    fn path_matches(&self, request: &Request<'_>) -> bool {
+        let segments = request.uri().path().segments();
+        if self.trailing {
+            if segments.len() + 1 < self.path.len() {
+                return false;
+            } //the trailing segment may be empty
+        } else if segments.len() != self.path.len() {
+            return false;
+        }
+
+        self.path
+            .iter()
+            .zip(segments)
+            .all(|(route_seg, request_seg)| match route_seg {
+                Segment::Static(value) => value == request_seg,
+                Segment::Dynamic => true,
+            })
+    }
+
+    fn query_matches(&self, request: &Request<'_>) -> bool {
+        self.query.iter().all(|(key, value)| {
+            request
+                .uri()
+                .query()
+                .is_some_and(|query| query.segments().any(|(k, v)| k == key && v == value))
+        })
+    }
+
+    fn format_matches(&self, request: &Request<'_>) -> bool {
+        let collide = |a: &MediaType, b: &MediaType| {
+            let collide = |a, b| a == "*" || b == "*" || a == b;
+            collide(a.top(), b.top()) && collide(a.sub(), b.sub())
+        };
+        match (&self.format, request.format()) {
+            (None, _) => true,
+            (Some(a), Some(b)) if !self.method.supports_payload() || b.specificity() == 2 => collide(a, b),
+            (Some(_), None) => !self.method.supports_payload(),
+            (Some(_), Some(_)) => false,
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use rocket::Request;
+
+/// Source of the identity of the session a request belongs to.
+///
+/// The `SessionBinder` trait allow you to bind csrf tokens to your application's sessions, by
+/// giving an identifier of the session of each request, such as a session id or the id of the
+/// logged in user. Tokens are authenticated along with this identifier, so tokens issued in a
+/// session are not valid in another one, nor after logout. It is implemented for closures, and is
+/// set via [`set_session_binder`].
+///
+/// [`set_session_binder`]: struct.CsrfFairingBuilder.html#method.set_session_binder
+///
+/// # Example
+///
+///  ```rust,no_run
+/// # extern crate rocket;
+/// # extern crate rocket_csrf;
+/// use rocket::Request;
+/// use rocket_csrf::CsrfFairingBuilder;
+///
+/// #[rocket::main]
+/// async fn main() {
+///     let _ = rocket::build()
+///         .attach(rocket_csrf::CsrfFairingBuilder::new()
+///                 .set_session_binder(|request: &Request<'_>| {
+///                     request.cookies().get("session_id").map(|cookie| cookie.value().as_bytes().to_vec())
+///                 })
+///                 .finalize().unwrap())
+///         //add your routes, other fairings...
+///         .launch()
+///         .await;
+/// }
+/// ```
+pub trait SessionBinder: Send + Sync + 'static {
+    /// Get the identifier of the session of a request, or `None` if it has none, for instance
+    /// before login or after logout.
+    fn session_id(&self, request: &Request<'_>) -> Option<Vec<u8>>;
+}
+
+impl<F> SessionBinder for F
+where
+    F: Fn(&Request<'_>) -> Option<Vec<u8>> + Send + Sync + 'static,
+{
+    fn session_id(&self, request: &Request<'_>) -> Option<Vec<u8>> {
+        self(request)
+    }
+}
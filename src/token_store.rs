@@ -0,0 +1,512 @@
+use data_encoding::HEXLOWER;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::crypto::CsrfProtection;
+
+/// Server-side store of the tokens issued to each session.
+///
+/// The `TokenStore` trait is used when tokens are [`TokenMode::Stored`]: tokens are then random
+/// ids, only valid while they are kept in the store for the session they were issued to. This
+/// allows to revoke every token of a session, for instance when a user log out everywhere, and
+/// to audit which tokens are outstanding. Sessions are given by the
+/// [SessionBinder](trait.SessionBinder.html) of the fairing, so revoking tokens of a user id
+/// revoke them on all of this user's devices.
+///
+/// Two implementations are provided, [MemoryTokenStore](struct.MemoryTokenStore.html), and
+/// [FileTokenStore](struct.FileTokenStore.html), which survives restarts. The store is also
+/// implemented for `Arc`s of stores, so the application can keep a handle on the store it gave
+/// to the fairing.
+///
+/// [`TokenMode::Stored`]: enum.TokenMode.html#variant.Stored
+///
+/// # Example
+///
+///  ```rust,no_run
+/// # #[macro_use] extern crate rocket;
+/// # extern crate rocket_csrf;
+/// use rocket::{Request, State};
+/// use rocket_csrf::{CsrfFairingBuilder, MemoryTokenStore, TokenStore};
+/// use std::sync::Arc;
+///
+/// #[post("/logout-everywhere/<user>")]
+/// async fn logout_everywhere(user: &str, store: &State<Arc<MemoryTokenStore>>) -> &'static str {
+///     //end the sessions of the user...
+///     match store.revoke(user.as_bytes()).await {
+///         Ok(()) => "logged out",
+///         Err(_) => "failed to revoke tokens",
+///     }
+/// }
+///
+/// #[rocket::main]
+/// async fn main() {
+///     let store = Arc::new(MemoryTokenStore::default());
+///     let _ = rocket::build()
+///         .attach(rocket_csrf::CsrfFairingBuilder::new()
+///                 .set_token_store(store.clone())
+///                 .set_session_binder(|request: &Request<'_>| {
+///                     request.cookies().get("user").map(|cookie| cookie.value().as_bytes().to_vec())
+///                 })
+///                 .finalize().unwrap())
+///         .manage(store)
+///         .mount("/", routes![logout_everywhere])
+///         //add your routes, other fairings...
+///         .launch()
+///         .await;
+/// }
+/// ```
+#[rocket::async_trait]
+pub trait TokenStore: Send + Sync + 'static {
+    /// Store a token issued to a session, which expires at `expires`, in seconds since the unix
+    /// epoch.
+    async fn insert(&self, session: &[u8], token: &[u8], expires: u64) -> io::Result<()>;
+
+    /// Get when a token issued to a session expires, or `None` if it was not issued to this
+    /// session, or was removed. Expired tokens may be forgotten.
+    async fn expiration(&self, session: &[u8], token: &[u8]) -> io::Result<Option<u64>>;
+
+    /// Remove a token issued to a session, returning whether it was present. This is how one-time
+    /// tokens are consumed, so it must be atomic.
+    async fn remove(&self, session: &[u8], token: &[u8]) -> io::Result<bool>;
+
+    /// Remove all tokens issued to a session.
+    async fn revoke(&self, session: &[u8]) -> io::Result<()>;
+
+    /// List the tokens issued to a session which were not removed, with their expiration.
+    async fn tokens(&self, session: &[u8]) -> io::Result<Vec<(Vec<u8>, u64)>>;
+}
+
+#[rocket::async_trait]
+impl<S: TokenStore + ?Sized> TokenStore for Arc<S> {
+    async fn insert(&self, session: &[u8], token: &[u8], expires: u64) -> io::Result<()> {
+        (**self).insert(session, token, expires).await
+    }
+
+    async fn expiration(&self, session: &[u8], token: &[u8]) -> io::Result<Option<u64>> {
+        (**self).expiration(session, token).await
+    }
+
+    async fn remove(&self, session: &[u8], token: &[u8]) -> io::Result<bool> {
+        (**self).remove(session, token).await
+    }
+
+    async fn revoke(&self, session: &[u8]) -> io::Result<()> {
+        (**self).revoke(session).await
+    }
+
+    async fn tokens(&self, session: &[u8]) -> io::Result<Vec<(Vec<u8>, u64)>> {
+        (**self).tokens(session).await
+    }
+}
+
+/// Most tokens a session may have outstanding, the oldest ones are removed when a new one is
+/// issued past this.
+const MAX_SESSION_TOKENS: usize = 64;
+
+/// Get a token for a session. If `reuse` is set, a token which stays valid for at least half the
+/// duration of tokens is reused, so that serving a page doesn't always store a new token, else,
+/// as for one-time tokens, a new one is always stored. Sessions keep at most
+/// `MAX_SESSION_TOKENS` tokens, so that loading pages can't grow the store without bound.
+pub async fn issue_token(
+    store: &dyn TokenStore,
+    engine: &CsrfProtection,
+    session: &[u8],
+    duration: u64,
+    reuse: bool,
+) -> io::Result<Vec<u8>> {
+    let now = now();
+    let mut tokens = store.tokens(session).await?;
+    if reuse {
+        let reusable = tokens
+            .iter()
+            .filter(|(_, expires)| *expires >= now + duration / 2)
+            .max_by_key(|(_, expires)| *expires);
+        if let Some((token, _)) = reusable {
+            return Ok(token.clone());
+        }
+    }
+    if tokens.len() >= MAX_SESSION_TOKENS {
+        tokens.sort_by_key(|(_, expires)| *expires);
+        for (token, _) in &tokens[..=tokens.len() - MAX_SESSION_TOKENS] {
+            store.remove(session, token).await?;
+        }
+    } //make room by removing the tokens expiring first, which are the oldest
+    let token = engine
+        .generate_stored_id()
+        .map_err(|_| io::Error::other("can't generate a random token"))?;
+    store.insert(session, &token, now + duration).await?;
+    Ok(token)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Tokens of each session, shared by the provided stores.
+#[derive(Clone, Default)]
+struct Tokens {
+    sessions: HashMap<Vec<u8>, HashMap<Vec<u8>, u64>>,
+    purged: u64,
+}
+
+impl Tokens {
+    fn insert(&mut self, session: &[u8], token: &[u8], expires: u64) {
+        let now = now();
+        if self.purged + 60 <= now {
+            self.purge(now);
+        } //forget expired tokens from time to time
+        self.sessions
+            .entry(session.to_vec())
+            .or_default()
+            .insert(token.to_vec(), expires);
+    }
+
+    fn expiration(&self, session: &[u8], token: &[u8]) -> Option<u64> {
+        self.sessions.get(session)?.get(token).copied()
+    }
+
+    fn remove(&mut self, session: &[u8], token: &[u8]) -> bool {
+        let tokens = match self.sessions.get_mut(session) {
+            Some(tokens) => tokens,
+            None => return false,
+        };
+        let removed = tokens.remove(token).is_some();
+        if tokens.is_empty() {
+            self.sessions.remove(session);
+        }
+        removed
+    }
+
+    fn revoke(&mut self, session: &[u8]) {
+        self.sessions.remove(session);
+    }
+
+    fn tokens(&self, session: &[u8]) -> Vec<(Vec<u8>, u64)> {
+        self.sessions
+            .get(session)
+            .map(|tokens| tokens.iter().map(|(token, &expires)| (token.clone(), expires)).collect())
+            .unwrap_or_default()
+    }
+
+    fn purge(&mut self, now: u64) {
+        for tokens in self.sessions.values_mut() {
+            tokens.retain(|_, expires| *expires > now);
+        }
+        self.sessions.retain(|_, tokens| !tokens.is_empty());
+        self.purged = now;
+    }
+
+    fn len(&self) -> usize {
+        self.sessions.values().map(HashMap::len).sum()
+    }
+
+    /// Serialize unexpired tokens, one [`entry`] per line.
+    fn serialize(&self, now: u64) -> String {
+        let mut out = String::new();
+        for (session, tokens) in &self.sessions {
+            for (token, expires) in tokens.iter().filter(|(_, &expires)| expires > now) {
+                out.push_str(&entry(session, token, *expires));
+            }
+        }
+        out
+    }
+
+    /// Replay entries, giving the tokens and whether the last entry was only partially written.
+    fn parse(content: &str, now: u64) -> io::Result<(Self, bool)> {
+        let mut tokens = Tokens::default();
+        let torn = !content.is_empty() && !content.ends_with('\n');
+        let lines = content.split_terminator('\n');
+        let complete = lines.clone().count() - usize::from(torn);
+        for line in lines.take(complete).filter(|line| !line.is_empty()) {
+            let mut fields = line.split(' ');
+            let parsed = match (fields.next(), fields.next(), fields.next(), fields.next()) {
+                (Some(session), Some(token), Some(expires), None) => HEXLOWER
+                    .decode(session.as_bytes())
+                    .ok()
+                    .zip(HEXLOWER.decode(token.as_bytes()).ok())
+                    .zip(expires.parse::<u64>().ok()),
+                _ => None,
+            };
+            let ((session, token), expires) = parsed.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("invalid token store entry `{}`", line))
+            })?;
+            if token.is_empty() {
+                tokens.revoke(&session);
+            } else if expires > now {
+                tokens.sessions.entry(session).or_default().insert(token, expires);
+            } else {
+                tokens.remove(&session, &token);
+            } //removed tokens are saved as expired
+        }
+        tokens.purged = now;
+        Ok((tokens, torn))
+    }
+}
+
+/// A line of a token store file, as hex encoded session and token, and expiration. Removed tokens
+/// are saved with a null expiration, and revoked sessions with an empty token.
+fn entry(session: &[u8], token: &[u8], expires: u64) -> String {
+    format!("{} {} {}\n", HEXLOWER.encode(session), HEXLOWER.encode(token), expires)
+}
+
+/// In memory [TokenStore](trait.TokenStore.html).
+///
+/// Tokens are lost when the application restarts, and are not shared between instances of an
+/// application. Expired tokens are forgotten at most a minute after they expire.
+#[derive(Default)]
+pub struct MemoryTokenStore {
+    tokens: Mutex<Tokens>,
+}
+
+impl MemoryTokenStore {
+    /// Create an empty store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> io::Result<std::sync::MutexGuard<'_, Tokens>> {
+        self.tokens.lock().map_err(|_| io::Error::other("token store lock poisoned"))
+    }
+}
+
+#[rocket::async_trait]
+impl TokenStore for MemoryTokenStore {
+    async fn insert(&self, session: &[u8], token: &[u8], expires: u64) -> io::Result<()> {
+        self.lock()?.insert(session, token, expires);
+        Ok(())
+    }
+
+    async fn expiration(&self, session: &[u8], token: &[u8]) -> io::Result<Option<u64>> {
+        Ok(self.lock()?.expiration(session, token))
+    }
+
+    async fn remove(&self, session: &[u8], token: &[u8]) -> io::Result<bool> {
+        Ok(self.lock()?.remove(session, token))
+    }
+
+    async fn revoke(&self, session: &[u8]) -> io::Result<()> {
+        self.lock()?.revoke(session);
+        Ok(())
+    }
+
+    async fn tokens(&self, session: &[u8]) -> io::Result<Vec<(Vec<u8>, u64)>> {
+        Ok(self.lock()?.tokens(session))
+    }
+}
+
+/// [TokenStore](trait.TokenStore.html) kept in memory and saved to a file, so that tokens survive
+/// restarts without requiring a database.
+///
+/// Each change is appended to the file, which is rewritten, through a temporary file next to it,
+/// once most of its lines are outdated, so this suits applications with a moderate number of
+/// sessions. It must not be shared between instances of an application.
+///
+/// # Example
+///
+///  ```rust,no_run
+/// # extern crate rocket;
+/// # extern crate rocket_csrf;
+/// use rocket::Request;
+/// use rocket_csrf::{CsrfFairingBuilder, FileTokenStore};
+///
+/// #[rocket::main]
+/// async fn main() {
+///     let _ = rocket::build()
+///         .attach(rocket_csrf::CsrfFairingBuilder::new()
+///                 .set_token_store(FileTokenStore::open("csrf-tokens").unwrap())
+///                 .set_session_binder(|request: &Request<'_>| {
+///                     request.cookies().get("user").map(|cookie| cookie.value().as_bytes().to_vec())
+///                 })
+///                 .finalize().unwrap())
+///         //add your routes, other fairings...
+///         .launch()
+///         .await;
+/// }
+/// ```
+pub struct FileTokenStore {
+    path: PathBuf,
+    file: tokio::sync::Mutex<TokenFile>,
+}
+
+/// Tokens of a file store, and the state of its file.
+struct TokenFile {
+    tokens: Tokens,
+    entries: usize, //lines in the file, including changes since it was last rewritten
+    torn: bool,     //an entry may have been partially written, the file must be rewritten
+}
+
+impl FileTokenStore {
+    /// Open a store saved at `path`, loading the tokens it contains, if the file exists.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let (tokens, entries, torn) = match fs::read_to_string(&path) {
+            Ok(content) => {
+                let (tokens, torn) = Tokens::parse(&content, now())?;
+                (tokens, content.lines().count(), torn)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (Tokens::default(), 0, false),
+            Err(e) => return Err(e),
+        };
+        Ok(FileTokenStore {
+            path,
+            file: tokio::sync::Mutex::new(TokenFile { tokens, entries, torn }),
+        })
+    }
+
+    async fn update<T>(&self, change: String, f: impl FnOnce(&mut Tokens) -> T) -> io::Result<T> {
+        //save a change and apply it, holding the lock so that saves happen in order, and memory
+        //only holds saved changes
+        let mut file = self.file.lock().await;
+        let path = self.path.clone();
+        if file.torn || (file.entries >= 1024 && file.entries > 2 * file.tokens.len()) {
+            let mut tokens = file.tokens.clone();
+            let result = f(&mut tokens);
+            let content = tokens.serialize(now());
+            let entries = content.lines().count();
+            tokio::task::spawn_blocking(move || {
+                let mut temporary = OsString::from(path.as_os_str());
+                temporary.push(".tmp");
+                fs::write(&temporary, content)?;
+                fs::rename(&temporary, &path) //never leave a partially written store
+            })
+            .await
+            .map_err(io::Error::other)??;
+            *file = TokenFile { tokens, entries, torn: false };
+            return Ok(result);
+        } //rewrite the file once most of its entries are outdated
+        let saved = tokio::task::spawn_blocking(move || {
+            fs::OpenOptions::new().create(true).append(true).open(&path)?.write_all(change.as_bytes())
+        })
+        .await
+        .map_err(io::Error::other)?;
+        if let Err(e) = saved {
+            file.torn = true;
+            return Err(e);
+        }
+        file.entries += 1;
+        Ok(f(&mut file.tokens))
+    }
+
+    async fn read<T>(&self, f: impl FnOnce(&Tokens) -> T) -> T {
+        f(&self.file.lock().await.tokens)
+    }
+}
+
+#[rocket::async_trait]
+impl TokenStore for FileTokenStore {
+    async fn insert(&self, session: &[u8], token: &[u8], expires: u64) -> io::Result<()> {
+        self.update(entry(session, token, expires), |tokens| tokens.insert(session, token, expires)).await
+    }
+
+    async fn expiration(&self, session: &[u8], token: &[u8]) -> io::Result<Option<u64>> {
+        Ok(self.read(|tokens| tokens.expiration(session, token)).await)
+    }
+
+    async fn remove(&self, session: &[u8], token: &[u8]) -> io::Result<bool> {
+        self.update(entry(session, token, 0), |tokens| tokens.remove(session, token)).await
+    }
+
+    async fn revoke(&self, session: &[u8]) -> io::Result<()> {
+        self.update(entry(session, &[], 0), |tokens| tokens.revoke(session)).await
+    }
+
+    async fn tokens(&self, session: &[u8]) -> io::Result<Vec<(Vec<u8>, u64)>> {
+        Ok(self.read(|tokens| tokens.tokens(session)).await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::CsrfProtection;
+    use crate::token_store::{issue_token, now, FileTokenStore, MemoryTokenStore, TokenStore, MAX_SESSION_TOKENS};
+
+    async fn exercise(store: &dyn TokenStore) {
+        let later = now() + 60;
+        store.insert(b"alice", b"a1", later).await.unwrap();
+        store.insert(b"alice", b"a2", later + 1).await.unwrap();
+        store.insert(b"bob", b"b1", later).await.unwrap();
+
+        assert_eq!(store.expiration(b"alice", b"a1").await.unwrap(), Some(later));
+        assert_eq!(store.expiration(b"bob", b"a1").await.unwrap(), None); //tokens belong to their session
+        let mut tokens = store.tokens(b"alice").await.unwrap();
+        tokens.sort();
+        assert_eq!(tokens, vec![(b"a1".to_vec(), later), (b"a2".to_vec(), later + 1)]);
+
+        assert!(store.remove(b"alice", b"a1").await.unwrap());
+        assert!(!store.remove(b"alice", b"a1").await.unwrap());
+        store.revoke(b"alice").await.unwrap();
+        assert!(store.tokens(b"alice").await.unwrap().is_empty());
+        assert_eq!(store.expiration(b"bob", b"b1").await.unwrap(), Some(later));
+    }
+
+    #[test]
+    fn test_issue_token() {
+        rocket::async_test(async {
+            let store = MemoryTokenStore::new();
+            let engine = CsrfProtection::from_keys([0; 32], Vec::new());
+            let first = issue_token(&store, &engine, b"alice", 30, true).await.unwrap();
+            assert_eq!(issue_token(&store, &engine, b"alice", 30, true).await.unwrap(), first);
+            for _ in 0..MAX_SESSION_TOKENS {
+                assert_ne!(issue_token(&store, &engine, b"alice", 60, false).await.unwrap(), first);
+            }
+            assert_eq!(store.tokens(b"alice").await.unwrap().len(), MAX_SESSION_TOKENS);
+            assert_eq!(store.expiration(b"alice", &first).await.unwrap(), None); //the oldest token was removed
+        })
+    }
+
+    #[test]
+    fn test_memory_store() {
+        rocket::async_test(async {
+            exercise(&MemoryTokenStore::new()).await;
+        })
+    }
+
+    #[test]
+    fn test_file_store() {
+        let path = std::env::temp_dir().join(format!("rocket_csrf_test_tokens_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        rocket::async_test(async {
+            exercise(&FileTokenStore::open(&path).unwrap()).await;
+
+            let store = FileTokenStore::open(&path).unwrap(); //tokens are loaded from the file
+            let tokens = store.tokens(b"bob").await.unwrap();
+            assert_eq!(tokens.iter().map(|(token, _)| &token[..]).collect::<Vec<_>>(), vec![b"b1"]);
+            assert!(store.tokens(b"alice").await.unwrap().is_empty());
+            store.insert(b"bob", b"expired", 1).await.unwrap();
+            assert_eq!(FileTokenStore::open(&path).unwrap().tokens(b"bob").await.unwrap().len(), 1);
+
+            let store = FileTokenStore::open(path.with_extension("missing").join("tokens")).unwrap(); //the store can't be saved
+            assert!(store.insert(b"bob", b"b2", now() + 60).await.is_err());
+            assert!(store.tokens(b"bob").await.unwrap().is_empty());
+
+            //changes are appended, and the file is rewritten once they outnumber tokens
+            let store = FileTokenStore::open(&path).unwrap();
+            for _ in 0..1500 {
+                store.insert(b"carol", b"c1", now() + 60).await.unwrap();
+            }
+            let lines = std::fs::read_to_string(&path).unwrap().lines().count();
+            assert!(lines < 1024, "{} lines", lines);
+            assert_eq!(FileTokenStore::open(&path).unwrap().tokens(b"carol").await.unwrap().len(), 1);
+
+            //a partially written entry is ignored, and overwritten by the next change
+            let mut content = std::fs::read_to_string(&path).unwrap();
+            content.push_str("6361726f6c 6332");
+            std::fs::write(&path, content).unwrap();
+            let store = FileTokenStore::open(&path).unwrap();
+            assert_eq!(store.tokens(b"carol").await.unwrap().len(), 1);
+            store.insert(b"carol", b"c3", now() + 60).await.unwrap();
+            assert_eq!(FileTokenStore::open(&path).unwrap().tokens(b"carol").await.unwrap().len(), 2);
+        });
+        std::fs::write(&path, "not a token\n").unwrap();
+        assert!(FileTokenStore::open(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}